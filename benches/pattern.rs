@@ -0,0 +1,70 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use drumchords::synth::{pattern::PatternEngine, NOTES_PER_CHANNEL, NUM_CHANNELS, PATTERN_LENGTH};
+
+const STEPS: u64 = 1024;
+
+fn triggered() -> [u16; NUM_CHANNELS] {
+    array_init::array_init(|c| (0b1010_0110_1001u16 >> (c % 4)) | 1)
+}
+
+// what the synth used to do on every step, recomputing the whole window with square waves
+fn recompute_pattern(triggered: u16, step: u64) -> u32 {
+    let get_beat = |beat: u64| {
+        let f = |b: u64| {
+            let mut a = false;
+            for n in 0..NOTES_PER_CHANNEL {
+                if triggered & (1 << n) != 0 {
+                    a ^= (b / (n + 1)) & 1 == 0;
+                }
+            }
+            a
+        };
+        f(beat) != f(beat.wrapping_sub(1))
+    };
+    let mut pattern = 0u32;
+    for b in 0..PATTERN_LENGTH {
+        if get_beat(step + b) {
+            pattern |= 1 << (PATTERN_LENGTH - b - 1);
+        }
+    }
+    pattern
+}
+
+fn patterns(c: &mut Criterion) {
+    let triggered = triggered();
+    let mut group = c.benchmark_group("patterns");
+    group.bench_function("recompute", |b| {
+        b.iter(|| {
+            for step in 0..STEPS {
+                for &t in &triggered {
+                    black_box(recompute_pattern(t, step));
+                }
+            }
+        })
+    });
+    group.bench_function("incremental", |b| {
+        b.iter(|| {
+            let mut engine = PatternEngine::new();
+            for step in 0..STEPS {
+                black_box(engine.update(step, &triggered));
+            }
+        })
+    });
+    group.bench_function("incremental with edits", |b| {
+        b.iter(|| {
+            let mut engine = PatternEngine::new();
+            let mut triggered = triggered;
+            for step in 0..STEPS {
+                // a note change every beat
+                if step % 4 == 0 {
+                    triggered[(step / 4) as usize % NUM_CHANNELS] ^= 1 << (step % 12);
+                }
+                black_box(engine.update(step, &triggered));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, patterns);
+criterion_main!(benches);
@@ -0,0 +1,72 @@
+use super::utils;
+use crate::synth::{self, midi_learn::Target};
+use eframe::egui;
+use std::sync::Arc;
+
+/// Right-clicking `response` binds the next incoming cc to `target`.
+pub fn learnable(response: egui::Response, config: &synth::Config, target: Target) {
+    if response.secondary_clicked() {
+        config.params.learning.store(Some(target));
+    }
+    response.on_hover_text("right-click to midi learn");
+}
+
+/// Shows what is being learned, if anything.
+pub fn learn_status(ui: &mut egui::Ui, config: &synth::Config) {
+    if let Some(target) = config.params.learning.load() {
+        ui.horizontal(|ui| {
+            ui.label(format!("move a control to bind it to {}", target));
+            if ui.small_button("✖").on_hover_text("cancel").clicked() {
+                config.params.learning.store(None);
+            }
+        });
+    }
+}
+
+/// Edit the ranges of the cc bindings of the current profile, or remove them.
+pub fn bindings_editor(ui: &mut egui::Ui, config: &synth::Config) {
+    let current = config.params.profile.lock().clone();
+    let mut edited = (*current).clone();
+    let mut remove = None;
+    for (i, binding) in edited.cc.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "ch {} cc {}: {}",
+                binding.midi_channel, binding.cc, binding.target
+            ));
+            match binding.target {
+                Target::Bpm | Target::Gain | Target::ChannelVolume(_) => {
+                    let suffix = if binding.target == Target::Bpm {
+                        ""
+                    } else {
+                        "dB"
+                    };
+                    ui.add(
+                        egui::DragValue::new(&mut binding.min)
+                            .prefix("min: ")
+                            .suffix(suffix)
+                            .speed(0.1),
+                    );
+                    ui.add(
+                        egui::DragValue::new(&mut binding.max)
+                            .prefix("max: ")
+                            .suffix(suffix)
+                            .speed(0.1),
+                    );
+                }
+                Target::Playing | Target::ChannelMute(_) | Target::ChannelSound(_) => {}
+            }
+            if ui.small_button("✖").clicked() {
+                remove = Some(i);
+            }
+        });
+    }
+    if let Some(i) = remove {
+        edited.cc.remove(i);
+    }
+    if edited != *current {
+        if let Err(e) = config.set_profile(Arc::new(edited)) {
+            utils::show_error("profile error", &e.to_string());
+        }
+    }
+}
@@ -1,9 +1,22 @@
+mod midi_learn;
 mod pattern_designer;
+mod profile_editor;
 mod toggle;
 mod utils;
-use crate::midi::MidiReader;
+use crate::midi::{
+    MidiOut, MidiReader, MidiState, HAS_VIRTUAL_PORTS, VIRTUAL_INPUT, VIRTUAL_OUTPUT,
+};
 use crate::periodic_updater::PeriodicUpdater;
-use crate::synth::{ChannelFeedback, Synth, PATTERN_LENGTH};
+use crate::synth::bounce::{self, BounceSettings};
+use crate::synth::kit::Kit;
+use crate::synth::midi_clock::ClockSource;
+use crate::synth::midi_export::{
+    self, LengthUnit, MidiExportSettings, NoteLayout, NoteLength, VelocitySource,
+};
+use crate::synth::midi_learn::Target;
+use crate::synth::note_mapping::Profile;
+use crate::synth::sound_bank::{Sound, UserSample};
+use crate::synth::{ChannelFeedback, Synth, UiEvent, PATTERN_LENGTH};
 use crate::{audio::AudioManager, synth};
 use cpal::traits::DeviceTrait;
 use crossbeam::channel;
@@ -16,7 +29,6 @@ use itertools::multizip;
 use log::warn;
 use parking_lot::Mutex;
 use pattern_designer::pattern_designer;
-use rfd::{MessageDialog, MessageLevel};
 use std::{collections::VecDeque, sync::Arc};
 
 const NAME: &str = "Drumchords";
@@ -26,17 +38,35 @@ const VIS_SIZE: usize = 512;
 enum Setting {
     Input,
     Output,
+    Export,
 }
 
 pub struct Data {
     setting_tab: Setting,
     audio: AudioManager<Synth>,
     midi: Arc<MidiReader>,
+    midi_out: Arc<MidiOut>,
+    /// led feedback for the controller, usually a different port than `midi_out`
+    feedback_out: Arc<MidiOut>,
     status_text: Arc<Mutex<String>>,
     forced_buffer_size: Option<u32>,
     left_vis_buffer: VecDeque<f32>,
     synth_config: Arc<synth::Config>,
     periodic_updater: Option<PeriodicUpdater>,
+    bounce_settings: BounceSettings,
+    midi_export_settings: MidiExportSettings,
+    /// the midi export dialog is shown
+    midi_export_open: bool,
+    /// kits that can be switched between, shared with the kit loading callback
+    kits: Arc<Mutex<Vec<Arc<Kit>>>>,
+    /// controller profiles that can be switched between
+    profiles: Arc<Mutex<Vec<Arc<Profile>>>>,
+    /// the active profile as last stored, see `utils::store_profile`
+    stored_profile: Arc<Profile>,
+    /// events from the synth that the ui handles, such as learned cc bindings
+    ui_events: channel::Receiver<UiEvent>,
+    /// messages for the synth to send to the midi output, such as state dumps
+    ui_midi: channel::Sender<wmidi::MidiMessage<'static>>,
 }
 
 pub enum Drumchords {
@@ -48,9 +78,35 @@ impl Drumchords {
     pub fn init(&mut self) {
         let (midi_tx, midi_rx) = channel::bounded(256);
         let midi = MidiReader::new(midi_tx.clone());
-        let synth = Synth::new(midi_rx);
+        let (midi_out_tx, midi_out_rx) = channel::bounded(1024);
+        let midi_out = MidiOut::new(midi_out_rx);
+        let mut synth = Synth::new(midi_rx);
+        synth.set_midi_output(midi_out_tx);
+        let (feedback_tx, feedback_rx) = channel::bounded(1024);
+        let feedback_out = MidiOut::new(feedback_rx);
+        synth.set_feedback_output(feedback_tx);
+        // a knob bound to a sound sends a burst of ccs
+        let (ui_tx, ui_rx) = channel::bounded(64);
+        synth.set_ui_output(ui_tx);
+        let (ui_midi_tx, ui_midi_rx) = channel::bounded(16);
+        synth.set_ui_midi(ui_midi_rx);
         let status_text = Arc::new(Mutex::new("".to_string()));
         let synth_config = synth.get_config();
+        let kits = Arc::new(Mutex::new(vec![synth_config.params.kit.lock().clone()]));
+        // pick up where the last run left off, along with its cc bindings
+        if let Some(profile) = utils::load_stored_profile() {
+            // stored profiles have been validated
+            let _ = synth_config.set_profile(Arc::new(profile));
+        }
+        let stored_profile = synth_config.params.profile.lock().clone();
+        let mut profiles = vec![stored_profile.clone()];
+        profiles.extend(
+            Profile::builtin()
+                .into_iter()
+                .filter(|p| *p != *stored_profile)
+                .map(Arc::new),
+        );
+        let profiles = Arc::new(Mutex::new(profiles));
         let status_clone = status_text.clone();
         let audio = AudioManager::new(synth, move |e| {
             *status_clone.lock() = e;
@@ -59,11 +115,21 @@ impl Drumchords {
             setting_tab: Setting::Output,
             audio,
             midi,
+            midi_out,
+            feedback_out,
             status_text,
             forced_buffer_size: None,
             left_vis_buffer: VecDeque::with_capacity(VIS_SIZE * 2),
             synth_config,
             periodic_updater: None,
+            bounce_settings: BounceSettings::default(),
+            midi_export_settings: MidiExportSettings::default(),
+            midi_export_open: false,
+            kits,
+            profiles,
+            stored_profile,
+            ui_events: ui_rx,
+            ui_midi: ui_midi_tx,
         }));
     }
 
@@ -77,6 +143,12 @@ impl Drumchords {
     }
 }
 
+impl Default for Drumchords {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl App for Drumchords {
     fn name(&self) -> &str {
         NAME
@@ -106,48 +178,339 @@ impl App for Drumchords {
                         if data.periodic_updater.is_none() {
                             data.periodic_updater = Some(PeriodicUpdater::new(frame.clone()));
                         }
+                        for event in data.ui_events.try_iter() {
+                            if let Some(reply) = data.synth_config.handle_ui_event(event) {
+                                let _ = data.ui_midi.try_send(reply);
+                            }
+                        }
+                        // keep the profile and its cc bindings for the next run
+                        let profile = data.synth_config.params.profile.lock().clone();
+                        if !Arc::ptr_eq(&profile, &data.stored_profile) {
+                            utils::store_profile(&profile);
+                            data.stored_profile = profile;
+                        }
                         // TODO nicer to use destructuring here?
                         let audio = &mut data.audio;
                         let midi = &data.midi;
+                        let midi_out = &data.midi_out;
+                        let feedback_out = &data.feedback_out;
                         let left_vis_buffer = &mut data.left_vis_buffer;
                         let forced_buffer_size = &mut data.forced_buffer_size;
                         let status_text = &data.status_text;
                         let config = data.synth_config.as_ref();
                         let setting_tab = &mut data.setting_tab;
                         let synth_config = &data.synth_config;
+                        let bounce_settings = &mut data.bounce_settings;
+                        let midi_export_settings = &mut data.midi_export_settings;
+                        let midi_export_open = &mut data.midi_export_open;
+                        let kits = &data.kits;
+                        let profiles = &data.profiles;
+                        let ui_midi = &data.ui_midi;
                         ui.horizontal(|ui| {
                             let mut playing = config.params.playing.load();
-                            ui.selectable_value(&mut playing, true, "▶");
-                            ui.selectable_value(&mut playing, false, "⏹");
-                            config.params.playing.store(playing);
+                            let response = ui.selectable_value(&mut playing, true, "▶")
+                                | ui.selectable_value(&mut playing, false, "⏹");
+                            // only store edits, to not undo changes made by midi since the load
+                            if response.changed() {
+                                config.params.playing.store(playing);
+                            }
+                            midi_learn::learnable(response, config, Target::Playing);
+
+                            if ui.button("💾 midi").clicked() {
+                                *midi_export_open = true;
+                            }
+                            midi_export_dialog(
+                                ctx,
+                                midi_export_open,
+                                midi_export_settings,
+                                synth_config,
+                            );
 
-                            if ui.button("💾").clicked() {
-                                match synth_config.generate_midi() {
-                                    Ok(midi) => {
-                                        utils::save_midi_file(&midi);
+                            if ui.button("💾 wav").clicked() {
+                                match synth_config.generate_wav(bounce_settings) {
+                                    Ok(wav) => {
+                                        utils::save_wav_file(&wav);
                                     }
                                     Err(e) => {
                                         warn!("{:?}", e);
-                                        let _ = MessageDialog::new()
-                                            .set_level(MessageLevel::Error)
-                                            .set_title("midi export error")
-                                            .set_description(e.to_string())
-                                            .show();
+                                        utils::show_error("error", &e.to_string());
                                     }
                                 }
                             }
+
+                            if ui.button("💾 project").clicked() {
+                                match synth_config.to_project().to_json() {
+                                    Ok(json) => {
+                                        utils::save_project_file(json.as_bytes());
+                                    }
+                                    Err(e) => {
+                                        warn!("{:?}", e);
+                                        utils::show_error("error", &e.to_string());
+                                    }
+                                }
+                            }
+
+                            if ui.button("💾 stems").clicked() {
+                                match synth_config.generate_stems(bounce_settings) {
+                                    Ok(stems) => {
+                                        utils::save_stems(&stems);
+                                    }
+                                    Err(e) => {
+                                        warn!("{:?}", e);
+                                        utils::show_error("error", &e.to_string());
+                                    }
+                                }
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label("kit:");
+                            let current = config.params.kit.lock().clone();
+                            let mut selected = None;
+                            ComboBox::from_id_source("kit_combo")
+                                .selected_text(current.name.as_str())
+                                .show_ui(ui, |ui| {
+                                    for kit in kits.lock().iter() {
+                                        if ui
+                                            .selectable_label(
+                                                Arc::ptr_eq(kit, &current),
+                                                kit.name.as_str(),
+                                            )
+                                            .clicked()
+                                        {
+                                            selected = Some(kit.clone());
+                                        }
+                                    }
+                                });
+                            if let Some(kit) = selected {
+                                if !Arc::ptr_eq(&kit, &current) {
+                                    config.set_kit(kit);
+                                }
+                            }
+                            if ui
+                                .small_button("📂")
+                                .on_hover_text("load kit manifest")
+                                .clicked()
+                            {
+                                let kits = kits.clone();
+                                let config = synth_config.clone();
+                                utils::open_kit(move |kit| {
+                                    let kit = Arc::new(kit);
+                                    kits.lock().push(kit.clone());
+                                    config.set_kit(kit);
+                                });
+                            }
                         });
+                        midi_learn::learn_status(ui, config);
                         ui.collapsing("settings:", |ui| {
                             ui.horizontal(|ui| {
                                 ui.selectable_value(setting_tab, Setting::Input, "input");
                                 ui.selectable_value(setting_tab, Setting::Output, "output");
+                                ui.selectable_value(setting_tab, Setting::Export, "export");
                             });
                             ui.separator();
                             match setting_tab {
                                 Setting::Input => {
+                                    let ports = midi.get_ports();
+                                    let mut remove = None;
+                                    for (index, input) in midi.get_inputs().into_iter().enumerate()
+                                    {
+                                        ui.horizontal(|ui| {
+                                            ui.label("midi:");
+                                            let mut selected = input.selected.clone();
+                                            ComboBox::from_id_source(
+                                                egui::Id::new("midi combo box").with(index),
+                                            )
+                                            .selected_text(
+                                                selected.as_deref().unwrap_or("first available"),
+                                            )
+                                            .show_ui(
+                                                ui,
+                                                |ui| {
+                                                    ui.selectable_value(
+                                                        &mut selected,
+                                                        None,
+                                                        "first available",
+                                                    );
+                                                    if HAS_VIRTUAL_PORTS {
+                                                        ui.selectable_value(
+                                                            &mut selected,
+                                                            Some(VIRTUAL_INPUT.to_string()),
+                                                            format!(
+                                                                "{} (virtual)",
+                                                                VIRTUAL_INPUT
+                                                            ),
+                                                        );
+                                                    }
+                                                    for name in &ports {
+                                                        ui.selectable_value(
+                                                            &mut selected,
+                                                            Some(name.clone()),
+                                                            name,
+                                                        );
+                                                    }
+                                                },
+                                            );
+                                            if selected != input.selected {
+                                                midi.select(index, selected);
+                                            }
+
+                                            let mut channel = input.settings.channel.load();
+                                            ComboBox::from_id_source(
+                                                egui::Id::new("midi channel").with(index),
+                                            )
+                                            .selected_text(match channel {
+                                                Some(c) => format!("ch {}", c.number()),
+                                                None => "all channels".to_string(),
+                                            })
+                                            .show_ui(
+                                                ui,
+                                                |ui| {
+                                                    ui.selectable_value(
+                                                        &mut channel,
+                                                        None,
+                                                        "all channels",
+                                                    );
+                                                    for c in (0..16).filter_map(|i| {
+                                                        wmidi::Channel::from_index(i).ok()
+                                                    }) {
+                                                        ui.selectable_value(
+                                                            &mut channel,
+                                                            Some(c),
+                                                            format!("ch {}", c.number()),
+                                                        );
+                                                    }
+                                                },
+                                            );
+                                            input.settings.channel.store(channel);
+
+                                            let mut transpose = input.settings.transpose.load();
+                                            ui.add(
+                                                egui::DragValue::new(&mut transpose)
+                                                    .prefix("transpose: ")
+                                                    .clamp_range(-48..=48),
+                                            );
+                                            input.settings.transpose.store(transpose);
+
+                                            let current =
+                                                input.settings.profile.lock().unwrap().clone();
+                                            let mut selected = current.clone();
+                                            ComboBox::from_id_source(
+                                                egui::Id::new("midi profile").with(index),
+                                            )
+                                            .selected_text(
+                                                current
+                                                    .as_ref()
+                                                    .map_or("active profile", |p| p.name.as_str()),
+                                            )
+                                            .show_ui(ui, |ui| {
+                                                if ui
+                                                    .selectable_label(
+                                                        current.is_none(),
+                                                        "active profile",
+                                                    )
+                                                    .clicked()
+                                                {
+                                                    selected = None;
+                                                }
+                                                for profile in profiles.lock().iter() {
+                                                    let is_current = current
+                                                        .as_ref()
+                                                        .is_some_and(|c| Arc::ptr_eq(c, profile));
+                                                    if ui
+                                                        .selectable_label(
+                                                            is_current,
+                                                            profile.name.as_str(),
+                                                        )
+                                                        .clicked()
+                                                    {
+                                                        selected = Some(profile.clone());
+                                                    }
+                                                }
+                                            })
+                                            .response
+                                            .on_hover_text("maps the notes of this input");
+                                            *input.settings.profile.lock().unwrap() = selected;
+
+                                            match &input.state {
+                                                MidiState::Connected(name) => {
+                                                    ui.label(format!("🔌 {}", name))
+                                                }
+                                                MidiState::Waiting(Some(name)) => {
+                                                    ui.label(format!("waiting for {}", name))
+                                                }
+                                                MidiState::Waiting(None) => {
+                                                    ui.label("no midi device")
+                                                }
+                                            };
+                                            if ui.small_button("✖").clicked() {
+                                                remove = Some(index);
+                                            }
+                                        });
+                                    }
+                                    if let Some(index) = remove {
+                                        midi.remove_input(index);
+                                    }
+                                    if ui.button("➕ input").clicked() {
+                                        midi.add_input();
+                                    }
+                                    let dropped = config.feedback.dropped_midi.load();
+                                    if dropped > 0 {
+                                        ui.label(format!(
+                                            "⚠ {} midi messages dropped, too many arrived at once",
+                                            dropped
+                                        ));
+                                    }
+                                    ui.separator();
+                                    profile_editor::profile_editor(ui, synth_config, profiles);
+                                    midi_learn::bindings_editor(ui, config);
                                     ui.horizontal(|ui| {
-                                        ui.label("midi:");
-                                        ui.label(midi.get_name());
+                                        ui.label("led feedback:");
+                                        output_port_combo(
+                                            ui,
+                                            "feedback combo box",
+                                            feedback_out,
+                                            false,
+                                        );
+                                        let mut send_leds = config.params.send_leds.load();
+                                        ui.checkbox(&mut send_leds, "send")
+                                            .on_hover_text("light the pads of the profile's notes");
+                                        config.params.send_leds.store(send_leds);
+                                    });
+                                    ui.separator();
+                                    egui::Grid::new("note modes").show(ui, |ui| {
+                                        for (channel, mode_atomic) in
+                                            config.params.channel_note_modes.iter().enumerate()
+                                        {
+                                            let mut mode = mode_atomic.load();
+                                            ui.label(format!("channel {}:", channel + 1));
+                                            ComboBox::from_id_source(
+                                                egui::Id::new("note mode").with(channel),
+                                            )
+                                            .selected_text(mode.to_string())
+                                            .show_ui(
+                                                ui,
+                                                |ui| {
+                                                    for m in enum_iterator::all::<
+                                                        synth::note_mapping::NoteMode,
+                                                    >(
+                                                    ) {
+                                                        ui.selectable_value(
+                                                            &mut mode,
+                                                            m,
+                                                            m.to_string(),
+                                                        );
+                                                    }
+                                                },
+                                            );
+                                            if mode != mode_atomic.load() {
+                                                mode_atomic.store(mode);
+                                                // don't leave notes of the previous mode hanging
+                                                config.selected[channel].store(0);
+                                            }
+                                            if channel % 4 == 3 {
+                                                ui.end_row();
+                                            }
+                                        }
                                     });
                                 }
                                 Setting::Output => {
@@ -214,6 +577,116 @@ impl App for Drumchords {
                                             });
                                         });
                                     });
+                                    ui.horizontal(|ui| {
+                                        ui.label("midi out:");
+                                        output_port_combo(ui, "midi out combo box", midi_out, true);
+                                        let mut send_clock = config.params.send_clock.load();
+                                        ui.checkbox(&mut send_clock, "send clock")
+                                            .on_hover_text(
+                                                "clock, start, stop and song position, unless following an external clock",
+                                            );
+                                        config.params.send_clock.store(send_clock);
+                                        let mut send_notes = config.params.send_notes.load();
+                                        ui.checkbox(&mut send_notes, "send notes")
+                                            .on_hover_text("play the hits on the midi output");
+                                        config.params.send_notes.store(send_notes);
+                                        if ui
+                                            .button("send state")
+                                            .on_hover_text("send the pattern state as a sysex dump")
+                                            .clicked()
+                                        {
+                                            let _ =
+                                                ui_midi.try_send(config.state_dump().to_midi());
+                                        }
+                                    });
+                                    egui::Grid::new("output notes").show(ui, |ui| {
+                                        for (channel, note_atomic) in
+                                            config.params.channel_output_notes.iter().enumerate()
+                                        {
+                                            let mut note = config.output_note(channel);
+                                            let mut changed = false;
+                                            ui.label(format!("channel {}:", channel + 1));
+                                            ui.horizontal(|ui| {
+                                                changed |= ui
+                                                    .add(
+                                                        egui::DragValue::new(&mut note.midi_channel)
+                                                            .prefix("ch ")
+                                                            .clamp_range(1..=16),
+                                                    )
+                                                    .changed();
+                                                changed |= ui
+                                                    .add(
+                                                        egui::DragValue::new(&mut note.note)
+                                                            .prefix("note ")
+                                                            .clamp_range(0..=127),
+                                                    )
+                                                    .changed();
+                                                if ui
+                                                    .add_enabled(
+                                                        note_atomic.load().is_some(),
+                                                        egui::Button::new("↺"),
+                                                    )
+                                                    .on_hover_text(
+                                                        "use the general midi drum note of the sound",
+                                                    )
+                                                    .clicked()
+                                                {
+                                                    config.set_output_note(channel, None);
+                                                }
+                                            });
+                                            if changed {
+                                                config.set_output_note(channel, Some(note));
+                                            }
+                                            if channel % 4 == 3 {
+                                                ui.end_row();
+                                            }
+                                        }
+                                    });
+                                }
+                                Setting::Export => {
+                                    ui.horizontal(|ui| {
+                                        ui.label("wav length:");
+                                        ui.add(
+                                            egui::DragValue::new(&mut bounce_settings.bars)
+                                                .speed(1)
+                                                .clamp_range(1..=1024)
+                                                .suffix(" bars"),
+                                        );
+                                    });
+                                    ui.horizontal(|ui| {
+                                        ui.label("sample rate:");
+                                        ComboBox::from_id_source("bounce sample rate combo")
+                                            .selected_text(bounce_settings.sample_rate.to_string())
+                                            .show_ui(ui, |ui| {
+                                                for rate in bounce::SAMPLE_RATES {
+                                                    ui.selectable_value(
+                                                        &mut bounce_settings.sample_rate,
+                                                        rate,
+                                                        rate.to_string(),
+                                                    );
+                                                }
+                                            });
+                                    });
+                                    ui.horizontal(|ui| {
+                                        ui.label("bit depth:");
+                                        ComboBox::from_id_source("bounce bit depth combo")
+                                            .selected_text(bounce_settings.bit_depth.to_string())
+                                            .show_ui(ui, |ui| {
+                                                for depth in
+                                                    enum_iterator::all::<bounce::BitDepth>()
+                                                {
+                                                    ui.selectable_value(
+                                                        &mut bounce_settings.bit_depth,
+                                                        depth,
+                                                        depth.to_string(),
+                                                    );
+                                                }
+                                            });
+                                    });
+                                    ui.checkbox(
+                                        &mut bounce_settings.dry_stems,
+                                        "dry stems (no volume, mute or gain)",
+                                    );
                                 }
                             };
                             ui.label(&*status_text.lock());
@@ -256,15 +729,36 @@ impl App for Drumchords {
                             ui.group(|ui| {
                                 ui.horizontal(|ui| {
                                     ui.label("bpm:");
+                                    let mut clock_source = config.params.clock_source.load();
+                                    let external = clock_source == ClockSource::External;
                                     let mut bpm = config.params.bpm.load();
                                     // TODO make fixed size
-                                    ui.add(
+                                    let response = ui.add_enabled(
+                                        !external,
                                         egui::DragValue::new(&mut bpm)
                                             .speed(1)
                                             .clamp_range(1..=1000)
                                             .max_decimals(0),
                                     );
-                                    config.params.bpm.store(bpm);
+                                    // the synth sets it when following an external clock
+                                    if response.changed() && !external {
+                                        config.params.bpm.store(bpm);
+                                    }
+                                    midi_learn::learnable(response, config, Target::Bpm);
+                                    ComboBox::from_id_source("clock source")
+                                        .selected_text(clock_source.to_string())
+                                        .show_ui(ui, |ui| {
+                                            for source in enum_iterator::all::<ClockSource>() {
+                                                ui.selectable_value(
+                                                    &mut clock_source,
+                                                    source,
+                                                    source.to_string(),
+                                                );
+                                            }
+                                        })
+                                        .response
+                                        .on_hover_text("follow the midi clock of the inputs");
+                                    config.params.clock_source.store(clock_source);
                                 });
                             });
                             ui.group(|ui| {
@@ -272,34 +766,42 @@ impl App for Drumchords {
                                     ui.label("gain:");
                                     let mut gain = config.params.gain_db.load();
                                     // TODO make fixed size
-                                    ui.add(
+                                    let response = ui.add(
                                         egui::DragValue::new(&mut gain)
                                             .suffix("dB")
                                             .speed(0.1)
                                             .min_decimals(1),
                                     );
-                                    config.params.gain_db.store(gain);
+                                    if response.changed() {
+                                        config.params.gain_db.store(gain);
+                                    }
+                                    midi_learn::learnable(response, config, Target::Gain);
                                 });
                             });
                         });
                         ui.group(|ui| {
                             ui.label("channels:");
                             ui.vertical(|ui| {
-                                let mut muted = config.params.muted.load();
+                                let loaded_muted = config.params.muted.load();
+                                let mut muted = loaded_muted;
                                 for (
                                     channel_id,
                                     ChannelFeedback { pattern },
                                     locked,
                                     feedback_selected,
                                     selected_sound_atomic,
+                                    voice_params_atomic,
                                     volume_atomic,
+                                    velocity_atomic,
                                 ) in multizip((
                                     0..,
                                     config.feedback.channels.iter(),
                                     config.params.locked.iter(),
                                     config.selected.iter(),
                                     config.params.channel_samples.iter(),
+                                    config.params.channel_voice_params.iter(),
                                     config.params.channel_volumes_db.iter(),
+                                    config.params.channel_velocities.iter(),
                                 )) {
                                     ui.horizontal(|ui| {
                                         {
@@ -331,7 +833,8 @@ impl App for Drumchords {
                                                 r = r.translate(vec2(cell_width + 1., 0f32));
                                             }
                                         }
-                                        let mut fg_pattern = locked.load();
+                                        let loaded_pattern = locked.load();
+                                        let mut fg_pattern = loaded_pattern;
                                         let bg_pattern = feedback_selected.load();
                                         pattern_designer(
                                             ui,
@@ -339,51 +842,144 @@ impl App for Drumchords {
                                             bg_pattern,
                                             synth::NOTES_PER_CHANNEL,
                                         );
-                                        locked.store(fg_pattern);
+                                        if fg_pattern != loaded_pattern {
+                                            locked.store(fg_pattern);
+                                        }
 
                                         // mute toggle
                                         let mut channel_muted = (muted >> channel_id) & 1 != 0;
-                                        toggle::toggle(ui, &mut channel_muted, "🔇");
-                                        muted =
-                                            muted & !(1 << channel_id) | u64::from(channel_muted);
+                                        let response = toggle::toggle(ui, &mut channel_muted, "🔇");
+                                        midi_learn::learnable(
+                                            response,
+                                            config,
+                                            Target::ChannelMute(channel_id),
+                                        );
+                                        muted = muted & !(1 << channel_id)
+                                            | u64::from(channel_muted) << channel_id;
 
                                         // volume
                                         let mut volume = volume_atomic.load();
                                         // TODO make fixed size
-                                        ui.add(
+                                        let response = ui.add(
                                             egui::DragValue::new(&mut volume)
                                                 .suffix("dB")
                                                 .speed(0.1)
                                                 .min_decimals(1),
                                         );
-                                        volume_atomic.store(volume);
+                                        if response.changed() {
+                                            volume_atomic.store(volume);
+                                        }
+                                        midi_learn::learnable(
+                                            response,
+                                            config,
+                                            Target::ChannelVolume(channel_id),
+                                        );
+
+                                        // velocity
+                                        let mut velocity = velocity_atomic.load();
+                                        if ui
+                                            .add(
+                                                egui::DragValue::new(&mut velocity)
+                                                    .prefix("vel: ")
+                                                    .clamp_range(1..=127),
+                                            )
+                                            .on_hover_text("picks the layer of kit samples")
+                                            .changed()
+                                        {
+                                            velocity_atomic.store(velocity);
+                                        }
 
                                         // sample selector
                                         let mut selected_sound = selected_sound_atomic.load();
-                                        ComboBox::from_id_source(
+                                        let selected_text = match selected_sound {
+                                            Sound::User => config.params.user_samples[channel_id]
+                                                .lock()
+                                                .as_ref()
+                                                .map(|s| s.name.clone())
+                                                .unwrap_or_else(|| "-".to_string()),
+                                            _ => config.sound_name(selected_sound),
+                                        };
+                                        let response = ComboBox::from_id_source(
                                             egui::Id::new(channel_id).with("sample_combo"),
                                         )
-                                        .selected_text(selected_sound.to_string())
-                                        .width(70f32)
-                                        .show_ui(
-                                            ui,
-                                            |ui| {
-                                                for s in
-                                                    enum_iterator::all::<synth::sound_bank::Sample>(
-                                                    )
-                                                {
-                                                    ui.selectable_value(
-                                                        &mut selected_sound,
-                                                        s,
-                                                        s.to_string(),
-                                                    );
-                                                }
-                                            },
+                                        .selected_text(selected_text)
+                                        .width(90f32)
+                                        .show_ui(ui, |ui| {
+                                            for s in config.available_sounds() {
+                                                ui.selectable_value(
+                                                    &mut selected_sound,
+                                                    s,
+                                                    config.sound_name(s),
+                                                );
+                                            }
+                                        })
+                                        .response;
+                                        midi_learn::learnable(
+                                            response,
+                                            config,
+                                            Target::ChannelSound(channel_id),
                                         );
-                                        selected_sound_atomic.store(selected_sound);
+                                        if selected_sound != selected_sound_atomic.load() {
+                                            if let Sound::Voice(voice) = selected_sound {
+                                                voice_params_atomic.store(voice.default_params());
+                                            }
+                                            config.set_channel_sound(channel_id, selected_sound);
+                                        }
+                                        if ui
+                                            .small_button("📂")
+                                            .on_hover_text("load wav file")
+                                            .clicked()
+                                        {
+                                            let config = synth_config.clone();
+                                            utils::open_file(
+                                                ("WAV audio", &["wav"]),
+                                                move |file| match UserSample::from_wav(
+                                                    file.name, file.path, &file.data,
+                                                ) {
+                                                    Ok(sample) => config.set_user_sample(
+                                                        channel_id,
+                                                        Some(Arc::new(sample)),
+                                                    ),
+                                                    Err(e) => utils::show_error(
+                                                        "sample load error",
+                                                        &e.to_string(),
+                                                    ),
+                                                },
+                                            );
+                                        }
+
+                                        // voice parameters
+                                        if let Sound::Voice(voice) = selected_sound {
+                                            let mut params = voice_params_atomic.load();
+                                            let [tune, decay, tone] = voice.param_info();
+                                            let mut changed = false;
+                                            for (value, info) in [
+                                                (&mut params.tune, tune),
+                                                (&mut params.decay, decay),
+                                                (&mut params.tone, tone),
+                                            ] {
+                                                let speed = (info.range.end() - info.range.start())
+                                                    / 200f32;
+                                                changed |= ui
+                                                    .add(
+                                                        egui::DragValue::new(value)
+                                                            .prefix(format!("{}: ", info.name))
+                                                            .speed(speed)
+                                                            .clamp_range(info.range)
+                                                            .max_decimals(3),
+                                                    )
+                                                    .changed();
+                                            }
+                                            if changed {
+                                                voice_params_atomic.store(params);
+                                            }
+                                        }
                                     });
                                 }
-                                config.params.muted.store(muted);
+                                // don't undo mutes done by midi cc since the load
+                                if muted != loaded_muted {
+                                    config.params.muted.store(muted);
+                                }
                             });
                         });
                     }
@@ -392,3 +988,139 @@ impl App for Drumchords {
         });
     }
 }
+
+/// Pick the port of `midi_out`, optionally offering our virtual output port.
+fn output_port_combo(ui: &mut egui::Ui, id: &str, midi_out: &MidiOut, virtual_port: bool) {
+    let (wanted, state) = midi_out.get_state();
+    let mut selected = wanted.clone();
+    ComboBox::from_id_source(id)
+        .selected_text(selected.as_deref().unwrap_or("none"))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut selected, None, "none");
+            if virtual_port && HAS_VIRTUAL_PORTS {
+                ui.selectable_value(
+                    &mut selected,
+                    Some(VIRTUAL_OUTPUT.to_string()),
+                    format!("{} (virtual)", VIRTUAL_OUTPUT),
+                );
+            }
+            for name in midi_out.get_ports() {
+                ui.selectable_value(&mut selected, Some(name.clone()), name);
+            }
+        });
+    if selected != wanted {
+        midi_out.select(selected);
+    }
+    if let Some(MidiState::Waiting(Some(name))) = state {
+        ui.label(format!("waiting for {}", name));
+    }
+}
+
+/// Window with the settings of the midi export and a button to export with them.
+fn midi_export_dialog(
+    ctx: &egui::CtxRef,
+    open: &mut bool,
+    settings: &mut MidiExportSettings,
+    config: &synth::Config,
+) {
+    let mut export = false;
+    egui::Window::new("midi export")
+        .open(open)
+        .collapsible(false)
+        .resizable(false)
+        .show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("notes:");
+                for layout in enum_iterator::all::<NoteLayout>() {
+                    ui.selectable_value(&mut settings.layout, layout, layout.to_string());
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("unit:");
+                for unit in enum_iterator::all::<LengthUnit>() {
+                    ui.selectable_value(&mut settings.unit, unit, unit.to_string());
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("start:");
+                ui.add(egui::DragValue::new(&mut settings.start).speed(1));
+            });
+            ui.horizontal(|ui| {
+                ui.label("note length:");
+                let length = &mut settings.note_length;
+                let ticks = matches!(length, NoteLength::Ticks(_));
+                if ui.selectable_label(ticks, "ticks").clicked() && !ticks {
+                    *length = NoteLength::Ticks(1);
+                }
+                let percent = matches!(length, NoteLength::Percent(_));
+                if ui.selectable_label(percent, "% of step").clicked() && !percent {
+                    *length = NoteLength::Percent(50);
+                }
+                ui.selectable_value(length, NoteLength::UntilNextHit, "until next hit");
+                match length {
+                    NoteLength::Ticks(ticks) => {
+                        ui.add(egui::DragValue::new(ticks).clamp_range(1..=65536));
+                    }
+                    NoteLength::Percent(percent) => {
+                        ui.add(
+                            egui::DragValue::new(percent)
+                                .clamp_range(1..=100)
+                                .suffix("%"),
+                        );
+                    }
+                    NoteLength::UntilNextHit => {}
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("velocity:");
+                for velocity in enum_iterator::all::<VelocitySource>() {
+                    ui.selectable_value(&mut settings.velocity, velocity, velocity.to_string());
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("resolution:");
+                ComboBox::from_id_source("midi export ppq combo")
+                    .selected_text(format!("{} ppq", settings.ppq))
+                    .show_ui(ui, |ui| {
+                        for ppq in midi_export::PPQS {
+                            ui.selectable_value(&mut settings.ppq, ppq, format!("{} ppq", ppq));
+                        }
+                    });
+            });
+            ui.checkbox(&mut settings.multi_track, "track per channel")
+                .on_hover_text("format 1 with a tempo track and a named track for each channel");
+            ui.checkbox(&mut settings.skip_muted, "skip muted channels");
+            ui.checkbox(&mut settings.one_period, "exactly one period")
+                .on_hover_text("until the pattern repeats, ignores the length and starts at step 1 at the earliest so that it loops");
+            ui.horizontal(|ui| {
+                ui.label("length:");
+                ui.add_enabled(
+                    !settings.one_period,
+                    egui::DragValue::new(&mut settings.length)
+                        .speed(1)
+                        .clamp_range(1..=65536),
+                );
+            });
+            match config.export_steps(settings) {
+                Ok(steps) => {
+                    ui.label(format!("steps {} to {}", steps.start, steps.end - 1));
+                }
+                Err(e) => {
+                    ui.label(e.to_string());
+                }
+            }
+            export = ui.button("💾 export").clicked();
+        });
+    if export {
+        match config.generate_midi(settings) {
+            Ok(midi) => {
+                utils::save_midi_file(&midi);
+                *open = false;
+            }
+            Err(e) => {
+                warn!("{:?}", e);
+                utils::show_error("error", &e.to_string());
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use super::utils;
+use crate::synth::{
+    self,
+    note_mapping::{KeyBinding, NoteMapping, Profile},
+    NOTES_PER_CHANNEL, NUM_CHANNELS,
+};
+use eframe::egui::{self, ComboBox};
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+fn mode_name(mapping: &NoteMapping) -> &'static str {
+    match mapping {
+        NoteMapping::Chromatic { .. } => "chromatic",
+        NoteMapping::ChannelPerDrum { .. } => "channel per drum",
+        NoteMapping::KeyMap { .. } => "key map",
+    }
+}
+
+/// Pick, edit, save and load the profile that maps midi notes to channels and divisors.
+pub fn profile_editor(
+    ui: &mut egui::Ui,
+    config: &Arc<synth::Config>,
+    profiles: &Arc<Mutex<Vec<Arc<Profile>>>>,
+) {
+    let current = config.params.profile.lock().clone();
+    let mut edited = (*current).clone();
+    ui.horizontal(|ui| {
+        ui.label("notes:");
+        let mut selected = None;
+        ComboBox::from_id_source("profile combo box")
+            .selected_text(current.name.as_str())
+            .show_ui(ui, |ui| {
+                for profile in profiles.lock().iter() {
+                    if ui
+                        .selectable_label(Arc::ptr_eq(profile, &current), profile.name.as_str())
+                        .clicked()
+                    {
+                        selected = Some(profile.clone());
+                    }
+                }
+            });
+        if let Some(profile) = selected {
+            edited = (*profile).clone();
+        }
+
+        ComboBox::from_id_source("mapping mode combo box")
+            .selected_text(mode_name(&edited.mapping))
+            .show_ui(ui, |ui| {
+                for mode in [
+                    NoteMapping::Chromatic { base_note: 0 },
+                    NoteMapping::ChannelPerDrum { base_note: 48 },
+                    NoteMapping::KeyMap { keys: vec![] },
+                ] {
+                    let name = mode_name(&mode);
+                    if ui
+                        .selectable_label(mode_name(&edited.mapping) == name, name)
+                        .clicked()
+                        && mode_name(&edited.mapping) != name
+                    {
+                        edited.mapping = mode;
+                    }
+                }
+            });
+        match &mut edited.mapping {
+            NoteMapping::Chromatic { base_note } => {
+                ui.add(
+                    egui::DragValue::new(base_note)
+                        .prefix("base note: ")
+                        .clamp_range(0..=127),
+                );
+            }
+            NoteMapping::ChannelPerDrum { base_note } => {
+                ui.add(
+                    egui::DragValue::new(base_note)
+                        .prefix("base note: ")
+                        .clamp_range(0..=128 - NOTES_PER_CHANNEL),
+                );
+            }
+            NoteMapping::KeyMap { .. } => {}
+        }
+
+        if ui
+            .small_button("💾")
+            .on_hover_text("save profile")
+            .clicked()
+        {
+            match current.to_json() {
+                Ok(json) => utils::save_profile_file(json.as_bytes()),
+                Err(e) => utils::show_error("profile save error", &e.to_string()),
+            }
+        }
+        if ui
+            .small_button("📂")
+            .on_hover_text("load profile")
+            .clicked()
+        {
+            let profiles = profiles.clone();
+            let config = config.clone();
+            utils::open_file(("Controller profile", &["json"]), move |file| {
+                let profile = std::str::from_utf8(&file.data)
+                    .map_err(anyhow::Error::from)
+                    .and_then(Profile::from_json);
+                match profile {
+                    Ok(profile) => {
+                        let profile = Arc::new(profile);
+                        profiles.lock().push(profile.clone());
+                        if let Err(e) = config.set_profile(profile) {
+                            utils::show_error("profile load error", &e.to_string());
+                        }
+                    }
+                    Err(e) => utils::show_error("profile load error", &e.to_string()),
+                }
+            });
+        }
+    });
+
+    if let NoteMapping::KeyMap { keys } = &mut edited.mapping {
+        let mut remove = None;
+        for (i, key) in keys.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                let channel_text = |c: Option<u8>| match c {
+                    Some(c) => format!("ch {}", c),
+                    None => "all channels".to_string(),
+                };
+                ComboBox::from_id_source(egui::Id::new("key midi channel").with(i))
+                    .selected_text(channel_text(key.midi_channel))
+                    .show_ui(ui, |ui| {
+                        for c in std::iter::once(None).chain((1..=16).map(Some)) {
+                            ui.selectable_value(&mut key.midi_channel, c, channel_text(c));
+                        }
+                    });
+                ui.add(
+                    egui::DragValue::new(&mut key.note)
+                        .prefix("note: ")
+                        .clamp_range(0..=127),
+                );
+                ui.add(
+                    egui::DragValue::new(&mut key.channel)
+                        .prefix("channel: ")
+                        .clamp_range(1..=NUM_CHANNELS),
+                );
+                ui.add(
+                    egui::DragValue::new(&mut key.divisor)
+                        .prefix("divisor: ")
+                        .clamp_range(1..=NOTES_PER_CHANNEL),
+                );
+                if ui.small_button("✖").clicked() {
+                    remove = Some(i);
+                }
+            });
+        }
+        if let Some(i) = remove {
+            keys.remove(i);
+        }
+        if ui.button("➕ key").clicked() {
+            keys.push(KeyBinding {
+                midi_channel: None,
+                note: keys
+                    .last()
+                    .map(|k| k.note.saturating_add(1).min(127))
+                    .unwrap_or(36),
+                channel: 1,
+                divisor: 1,
+            });
+        }
+    }
+
+    ui.horizontal(|ui| {
+        ui.label("led velocities:");
+        let leds = &mut edited.leds;
+        for (value, name) in [
+            (&mut leds.off, "off: "),
+            (&mut leds.locked, "locked: "),
+            (&mut leds.selected, "selected: "),
+            (&mut leds.hit, "hit: "),
+        ] {
+            ui.add(
+                egui::DragValue::new(value)
+                    .prefix(name)
+                    .clamp_range(0..=127),
+            );
+        }
+    });
+
+    if edited != *current {
+        // keep the identity of unedited profiles so the combo box can tell which one is selected
+        let profile = profiles
+            .lock()
+            .iter()
+            .find(|p| ***p == edited)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(edited));
+        if let Err(e) = config.set_profile(profile) {
+            utils::show_error("profile error", &e.to_string());
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use eframe::egui::{self, vec2, Align2, Rgba, TextStyle};
 
-pub fn toggle(ui: &mut egui::Ui, on: &mut bool, text: &str) {
+pub fn toggle(ui: &mut egui::Ui, on: &mut bool, text: &str) -> egui::Response {
     let y = ui.spacing().interact_size.y * 0.75;
     let (rect, response) = ui.allocate_exact_size(vec2(y, y), egui::Sense::click());
     if response.clicked() {
@@ -25,4 +25,5 @@ pub fn toggle(ui: &mut egui::Ui, on: &mut bool, text: &str) {
         TextStyle::Button,
         text_color,
     );
+    response
 }
@@ -1,8 +1,9 @@
+use crate::synth::{bounce::Stem, kit::Kit, note_mapping::Profile};
 use anyhow::Result;
 use rfd::{MessageDialog, MessageLevel};
 
 #[cfg(target_arch = "wasm32")]
-fn save_midi_file_impl(data: &[u8]) -> Result<()> {
+fn save_file_impl(data: &[u8], file_name: &str, _filter: (&str, &[&str])) -> Result<()> {
     use anyhow::anyhow;
     use eframe::wasm_bindgen::{JsCast, JsValue};
     use js_sys::{Array, Uint8Array};
@@ -12,7 +13,6 @@ fn save_midi_file_impl(data: &[u8]) -> Result<()> {
         let blob =
             Blob::new_with_u8_array_sequence(&JsValue::from(Array::of1(&Uint8Array::from(data))))
                 .expect("file blob");
-        let file_name = "clip.mid";
         let window = web_sys::window().expect("no global `window` exists");
         let document = window.document().expect("should have a document on window");
         let body = document.body().expect("body");
@@ -33,12 +33,13 @@ fn save_midi_file_impl(data: &[u8]) -> Result<()> {
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-fn save_midi_file_impl(data: &[u8]) -> Result<()> {
+fn save_file_impl(data: &[u8], file_name: &str, filter: (&str, &[&str])) -> Result<()> {
     use rfd::FileDialog;
     use std::io::Write;
 
     if let Some(path) = FileDialog::new()
-        .add_filter("Standard MIDI File", &["mid", "midi"])
+        .add_filter(filter.0, filter.1)
+        .set_file_name(file_name)
         .save_file()
     {
         let mut f = std::fs::File::create(path)?;
@@ -47,12 +48,306 @@ fn save_midi_file_impl(data: &[u8]) -> Result<()> {
     Ok(())
 }
 
+fn save_file(data: &[u8], file_name: &str, filter: (&str, &[&str])) {
+    if let Err(e) = save_file_impl(data, file_name, filter) {
+        show_error("error", &e.to_string());
+    }
+}
+
 pub fn save_midi_file(data: &[u8]) {
-    if let Err(e) = save_midi_file_impl(data) {
-        let _ = MessageDialog::new()
-            .set_level(MessageLevel::Error)
-            .set_title("error")
-            .set_description(e.to_string())
-            .show();
+    save_file(data, "clip.mid", ("Standard MIDI File", &["mid", "midi"]));
+}
+
+pub fn save_project_file(data: &[u8]) {
+    save_file(data, "project.json", ("Drumchords project", &["json"]));
+}
+
+pub fn save_profile_file(data: &[u8]) {
+    save_file(data, "profile.json", ("Controller profile", &["json"]));
+}
+
+pub fn save_wav_file(data: &[u8]) {
+    save_file(data, "clip.wav", ("WAV audio", &["wav"]));
+}
+
+#[cfg(target_arch = "wasm32")]
+fn save_stems_impl(stems: &[Stem]) -> Result<()> {
+    use std::io::Write;
+    // can't write several files from the browser, so zip them up
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for stem in stems {
+        zip.start_file(stem.file_name.as_str(), options)?;
+        zip.write_all(&stem.data)?;
+    }
+    let data = zip.finish()?.into_inner();
+    save_file_impl(&data, "stems.zip", ("Zip archive", &["zip"]))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn save_stems_impl(stems: &[Stem]) -> Result<()> {
+    use rfd::FileDialog;
+
+    if let Some(dir) = FileDialog::new().pick_folder() {
+        for stem in stems {
+            std::fs::write(dir.join(&stem.file_name), &stem.data)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn save_stems(stems: &[Stem]) {
+    if let Err(e) = save_stems_impl(stems) {
+        show_error("error", &e.to_string());
+    }
+}
+
+/// Kept between runs, in the user's config directory or in the browser's local storage.
+const STORED_PROFILE: &str = "profile.json";
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok().flatten()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn load_stored(name: &str) -> Option<String> {
+    local_storage()?
+        .get_item(&format!("drumchords/{}", name))
+        .ok()
+        .flatten()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn store(name: &str, data: &str) -> Result<()> {
+    use anyhow::anyhow;
+    local_storage()
+        .ok_or_else(|| anyhow!("no local storage"))?
+        .set_item(&format!("drumchords/{}", name), data)
+        .map_err(|_| anyhow!("unable to store {}", name))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn stored_path(name: &str) -> Option<std::path::PathBuf> {
+    use std::{env, path::PathBuf};
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .or_else(|| env::var_os("APPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("drumchords").join(name))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_stored(name: &str) -> Option<String> {
+    std::fs::read_to_string(stored_path(name)?).ok()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn store(name: &str, data: &str) -> Result<()> {
+    use anyhow::anyhow;
+    let path = stored_path(name).ok_or_else(|| anyhow!("no config directory"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, data)?;
+    Ok(())
+}
+
+/// The profile that was active when the app last ran, if any.
+pub fn load_stored_profile() -> Option<Profile> {
+    let json = load_stored(STORED_PROFILE)?;
+    match Profile::from_json(&json) {
+        Ok(profile) => Some(profile),
+        Err(e) => {
+            log::warn!("ignoring stored profile: {}", e);
+            None
+        }
+    }
+}
+
+/// Keep `profile` for the next run. Runs without asking, so errors are only logged.
+pub fn store_profile(profile: &Profile) {
+    if let Err(e) = profile
+        .to_json()
+        .and_then(|json| store(STORED_PROFILE, &json))
+    {
+        log::warn!("unable to store profile: {}", e);
+    }
+}
+
+pub fn show_error(title: &str, description: &str) {
+    let _ = MessageDialog::new()
+        .set_level(MessageLevel::Error)
+        .set_title(title)
+        .set_description(description)
+        .show();
+}
+
+pub struct OpenedFile {
+    pub name: String,
+    /// not available on the web
+    pub path: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// Let the user pick files and call `on_open` with their contents once all of them have been read.
+#[cfg(target_arch = "wasm32")]
+fn open_files<F>(extensions: &[&str], multiple: bool, on_open: F)
+where
+    F: FnOnce(Vec<OpenedFile>) + 'static,
+{
+    use eframe::wasm_bindgen::{prelude::Closure, JsCast, JsValue};
+    use js_sys::Uint8Array;
+    use std::{cell::RefCell, rc::Rc};
+    use web_sys::{FileReader, HtmlInputElement};
+
+    let r = (|| -> Result<(), JsValue> {
+        let window = web_sys::window().expect("no global `window` exists");
+        let document = window.document().expect("should have a document on window");
+        let input = document
+            .create_element("input")?
+            .dyn_into::<HtmlInputElement>()?;
+        input.set_type("file");
+        input.set_multiple(multiple);
+        let accept: Vec<String> = extensions.iter().map(|e| format!(".{}", e)).collect();
+        input.set_accept(&accept.join(","));
+        let input_clone = input.clone();
+        let onchange = Closure::once(move || {
+            let files = match input_clone.files() {
+                Some(files) if files.length() > 0 => files,
+                _ => return,
+            };
+            let count = files.length() as usize;
+            // the reads finish in any order
+            let opened = Rc::new(RefCell::new(Vec::with_capacity(count)));
+            let on_open = Rc::new(RefCell::new(Some(on_open)));
+            for file in (0..files.length()).filter_map(|i| files.get(i)) {
+                let reader = FileReader::new().expect("file reader");
+                let reader_clone = reader.clone();
+                let name = file.name();
+                let opened = opened.clone();
+                let on_open = on_open.clone();
+                let onload = Closure::once(move || match reader_clone.result() {
+                    Ok(result) => {
+                        let mut opened = opened.borrow_mut();
+                        opened.push(OpenedFile {
+                            name,
+                            path: None,
+                            data: Uint8Array::new(&result).to_vec(),
+                        });
+                        if opened.len() == count {
+                            let files = std::mem::take(&mut *opened);
+                            drop(opened);
+                            if let Some(on_open) = on_open.borrow_mut().take() {
+                                on_open(files);
+                            }
+                        }
+                    }
+                    Err(_) => show_error("error", "unable to read file"),
+                });
+                reader.set_onload(Some(onload.as_ref().unchecked_ref()));
+                // the closures only run once, leaking them is simpler than keeping track of them
+                onload.forget();
+                if reader.read_as_array_buffer(&file).is_err() {
+                    show_error("error", "unable to read file");
+                }
+            }
+        });
+        input.set_onchange(Some(onchange.as_ref().unchecked_ref()));
+        onchange.forget();
+        input.click();
+        Ok(())
+    })();
+    if let Err(e) = r {
+        show_error(
+            "error",
+            &e.as_string().unwrap_or_else(|| "unknown error".into()),
+        );
+    }
+}
+
+/// Let the user pick a file and call `on_open` with its contents.
+/// `on_open` is called later on wasm, once the browser has read the file.
+#[cfg(target_arch = "wasm32")]
+pub fn open_file<F>(filter: (&str, &[&str]), on_open: F)
+where
+    F: FnOnce(OpenedFile) + 'static,
+{
+    open_files(filter.1, false, move |files| {
+        if let Some(file) = files.into_iter().next() {
+            on_open(file);
+        }
+    });
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn open_file<F>(filter: (&str, &[&str]), on_open: F)
+where
+    F: FnOnce(OpenedFile) + 'static,
+{
+    use rfd::FileDialog;
+
+    if let Some(path) = FileDialog::new().add_filter(filter.0, filter.1).pick_file() {
+        match std::fs::read(&path) {
+            Ok(data) => on_open(OpenedFile {
+                name: path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                path: Some(path.display().to_string()),
+                data,
+            }),
+            Err(e) => show_error("error", &e.to_string()),
+        }
+    }
+}
+
+/// Let the user pick a kit manifest and call `on_open` with the loaded kit.
+/// The browser can't read files next to the manifest, so there the samples have to be picked along with it.
+#[cfg(target_arch = "wasm32")]
+pub fn open_kit<F>(on_open: F)
+where
+    F: FnOnce(Kit) + 'static,
+{
+    use anyhow::anyhow;
+
+    open_files(&["json", "wav"], true, move |files| {
+        let kit = (|| -> Result<Kit> {
+            let manifest = files
+                .iter()
+                .find(|f| f.name.ends_with(".json"))
+                .ok_or_else(|| anyhow!("no kit manifest selected"))?;
+            Kit::from_manifest(std::str::from_utf8(&manifest.data)?, None, |file| {
+                let name = file.rsplit('/').next().unwrap_or(file);
+                files
+                    .iter()
+                    .find(|f| f.name == name)
+                    .map(|f| f.data.clone())
+                    .ok_or_else(|| anyhow!("{} was not selected", file))
+            })
+        })();
+        match kit {
+            Ok(kit) => on_open(kit),
+            Err(e) => show_error("kit load error", &format!("{:#}", e)),
+        }
+    });
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn open_kit<F>(on_open: F)
+where
+    F: FnOnce(Kit) + 'static,
+{
+    use rfd::FileDialog;
+
+    if let Some(path) = FileDialog::new()
+        .add_filter("Drum kit", &["json"])
+        .pick_file()
+    {
+        match Kit::from_file(&path) {
+            Ok(kit) => on_open(kit),
+            Err(e) => show_error("kit load error", &format!("{:#}", e)),
+        }
     }
 }
@@ -108,6 +108,7 @@ where
                     let sample_rate = sample_rate.0;
                     let channels = config.channels.into();
                     let mut synth = self.synth.clone();
+                    synth.set_sample_rate(sample_rate);
                     let error_callback = self.error_callback.clone();
                     let buffer_size = self.buffer_size.clone();
                     let (mut left_vis_prod, left_vis_cons) =
@@ -0,0 +1,163 @@
+// renders a saved project to wav and/or midi files without opening a window or an audio device
+
+#![warn(clippy::all, rust_2018_idioms)]
+
+use anyhow::{anyhow, bail, Context, Result};
+use drumchords::synth::{
+    bounce::{BitDepth, BounceSettings},
+    midi_export::{MidiExportSettings, NoteLayout, NoteLength, VelocitySource},
+    project::Project,
+    Config,
+};
+use log::info;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const USAGE: &str = "usage: drumchords-render <project.json> [options]
+
+options:
+    --wav <file>           render the mix to a wav file
+    --stems <dir>          render one wav file per channel into a directory
+    --midi <file>          export a midi file
+    --midi-length <bars>   length of the midi file in bars or \"period\" (default 64)
+    --midi-start <bars>    bar to start the midi file at (default 0)
+    --midi-keys            export a white key per channel instead of general midi drums
+    --midi-tracks          export a track per channel
+    --midi-skip-muted      leave muted channels out of the midi file
+    --midi-ppq <n>         ticks per beat of the midi file, a multiple of 4 (default 96)
+    --midi-note-length <length>
+                           ticks, percentage of a step like 50% or \"next\" (default 50%)
+    --midi-velocity <source>
+                           full, channel or volume (default channel)
+    --bars <n>             length of the wav files in bars (default 4)
+    --sample-rate <hz>     sample rate of the wav files (default 48000)
+    --bit-depth <depth>    16, 24 or 32f (default 16)
+    --dry                  render stems without volume, mute and gain";
+
+struct Args {
+    project: PathBuf,
+    wav: Option<PathBuf>,
+    stems: Option<PathBuf>,
+    midi: Option<PathBuf>,
+    bounce_settings: BounceSettings,
+    midi_export_settings: MidiExportSettings,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args> {
+    let mut project = None;
+    let mut wav = None;
+    let mut stems = None;
+    let mut midi = None;
+    let mut bounce_settings = BounceSettings::default();
+    let mut midi_export_settings = MidiExportSettings::default();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow!("missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--wav" => wav = Some(value()?.into()),
+            "--stems" => stems = Some(value()?.into()),
+            "--midi" => midi = Some(value()?.into()),
+            "--midi-length" => match value()?.as_str() {
+                "period" => midi_export_settings.one_period = true,
+                bars => {
+                    midi_export_settings.length = bars.parse().context("invalid --midi-length")?
+                }
+            },
+            "--midi-keys" => midi_export_settings.layout = NoteLayout::Keys,
+            "--midi-tracks" => midi_export_settings.multi_track = true,
+            "--midi-skip-muted" => midi_export_settings.skip_muted = true,
+            "--midi-ppq" => {
+                midi_export_settings.ppq = value()?.parse().context("invalid --midi-ppq")?
+            }
+            "--midi-note-length" => {
+                let length = value()?;
+                let invalid = || anyhow!("invalid --midi-note-length {}", length);
+                midi_export_settings.note_length = if length == "next" {
+                    NoteLength::UntilNextHit
+                } else if let Some(percent) = length.strip_suffix('%') {
+                    NoteLength::Percent(percent.parse().map_err(|_| invalid())?)
+                } else {
+                    NoteLength::Ticks(length.parse().map_err(|_| invalid())?)
+                };
+            }
+            "--midi-velocity" => {
+                midi_export_settings.velocity = match value()?.as_str() {
+                    "full" => VelocitySource::Full,
+                    "channel" => VelocitySource::Channel,
+                    "volume" => VelocitySource::Volume,
+                    other => bail!("invalid --midi-velocity {}", other),
+                }
+            }
+            "--midi-start" => {
+                midi_export_settings.start = value()?.parse().context("invalid --midi-start")?
+            }
+            "--bars" => bounce_settings.bars = value()?.parse().context("invalid --bars")?,
+            "--sample-rate" => {
+                bounce_settings.sample_rate = value()?.parse().context("invalid --sample-rate")?
+            }
+            "--bit-depth" => {
+                bounce_settings.bit_depth = match value()?.as_str() {
+                    "16" => BitDepth::Int16,
+                    "24" => BitDepth::Int24,
+                    "32f" => BitDepth::Float32,
+                    other => bail!("invalid --bit-depth {}", other),
+                }
+            }
+            "--dry" => bounce_settings.dry_stems = true,
+            "-h" | "--help" => bail!("{}", USAGE),
+            _ if arg.starts_with('-') => bail!("unknown option {}\n\n{}", arg, USAGE),
+            _ if project.is_none() => project = Some(arg.into()),
+            _ => bail!("unexpected argument {}\n\n{}", arg, USAGE),
+        }
+    }
+    let project = project.ok_or_else(|| anyhow!("no project given\n\n{}", USAGE))?;
+    if wav.is_none() && stems.is_none() && midi.is_none() {
+        bail!(
+            "nothing to render, use --wav, --stems or --midi\n\n{}",
+            USAGE
+        );
+    }
+    Ok(Args {
+        project,
+        wav,
+        stems,
+        midi,
+        bounce_settings,
+        midi_export_settings,
+    })
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let args = parse_args(std::env::args().skip(1))?;
+    let data = fs::read_to_string(&args.project)
+        .with_context(|| format!("unable to read {}", args.project.display()))?;
+    let config = Config::new();
+    let project = Project::from_json(&data)?;
+    project.load_into(
+        &config,
+        args.project.parent().unwrap_or_else(|| Path::new("")),
+    )?;
+
+    if let Some(path) = &args.wav {
+        fs::write(path, config.generate_wav(&args.bounce_settings)?)?;
+        info!("wrote {}", path.display());
+    }
+    if let Some(dir) = &args.stems {
+        fs::create_dir_all(dir)?;
+        for stem in config.generate_stems(&args.bounce_settings)? {
+            let path = dir.join(&stem.file_name);
+            fs::write(&path, &stem.data)?;
+            info!("wrote {}", path.display());
+        }
+    }
+    if let Some(path) = &args.midi {
+        fs::write(path, config.generate_midi(&args.midi_export_settings)?)?;
+        info!("wrote {}", path.display());
+    }
+    Ok(())
+}
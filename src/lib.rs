@@ -1,21 +1,21 @@
-// this file is used when targeting wasm
-
 #![warn(clippy::all, rust_2018_idioms)]
-cfg_if::cfg_if! {
-if #[cfg(target_arch = "wasm32")] {
-
-use log::{warn, Level, Metadata, Record};
-use web_sys::console;
 
+mod app;
 mod audio;
 mod midi;
-mod synth;
 mod periodic_updater;
-    mod timer;
+pub mod synth;
+mod timer;
 
-mod app;
 pub use app::Drumchords;
 
+// the rest of this file is used when targeting wasm
+cfg_if::cfg_if! {
+if #[cfg(target_arch = "wasm32")] {
+
+use log::{warn, Level, Metadata, Record};
+use web_sys::console;
+
 struct WebLogger;
 // isn't there a ready made crate for this functionality somewhere?
 impl log::Log for WebLogger {
@@ -1,16 +1,8 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
-mod audio;
-mod midi;
-mod periodic_updater;
-mod synth;
-mod timer;
-
-mod app;
-use app::Drumchords;
-
 #[cfg(not(target_arch = "wasm32"))]
 fn main() {
+    use drumchords::Drumchords;
     use eframe::{egui::Vec2, epi};
 
     env_logger::init();
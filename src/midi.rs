@@ -1,83 +1,558 @@
-use crate::timer::Timer;
+use crate::{
+    synth::{midi_out::OutputEvent, note_mapping::Profile, InputEvent},
+    timer::{self, Timer},
+};
 use anyhow::{anyhow, bail, Result};
 use chrono::Duration;
-use crossbeam::channel;
-use log::{error, warn};
-use midir::{MidiInput, MidiInputConnection};
+use crossbeam::{atomic::AtomicCell, channel};
+use log::{error, info, warn};
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
 use std::{
     convert::TryFrom,
     sync::{Arc, Mutex},
 };
 use wmidi::MidiMessage;
 
-type MidiSender = channel::Sender<MidiMessage<'static>>;
+type MidiSender = channel::Sender<InputEvent>;
+
+const CLIENT_NAME: &str = "Drumchords";
+/// ports other software can connect to, where supported
+pub const VIRTUAL_INPUT: &str = "Drumchords In";
+pub const VIRTUAL_OUTPUT: &str = "Drumchords Out";
+pub const HAS_VIRTUAL_PORTS: bool = cfg!(target_os = "linux");
+
+/// Our own virtual ports show up among the ports, connecting to them would only loop back.
+fn is_own_port(name: &str) -> bool {
+    [VIRTUAL_INPUT, VIRTUAL_OUTPUT]
+        .iter()
+        .any(|port| name.starts_with(&format!("{}:{} ", CLIENT_NAME, port)))
+}
+
+pub enum MidiState {
+    Connected(String),
+    /// the chosen port isn't available, will connect once it shows up
+    Waiting(Option<String>),
+}
+
+/// Per input settings that can be changed while it's connected.
+pub struct InputSettings {
+    /// only pass on messages on this channel, or on all channels if None
+    pub channel: AtomicCell<Option<wmidi::Channel>>,
+    /// semitones added to incoming notes
+    pub transpose: AtomicCell<i8>,
+    /// maps the notes of this input instead of the active profile of the synth, if set
+    pub profile: Mutex<Option<Arc<Profile>>>,
+}
+
+impl Default for InputSettings {
+    fn default() -> Self {
+        Self {
+            channel: AtomicCell::new(None),
+            transpose: AtomicCell::new(0),
+            profile: Mutex::new(None),
+        }
+    }
+}
+
+impl InputSettings {
+    /// Apply the channel filter, transposition and note mapping, None if the message should be dropped.
+    /// Along with the message comes the drum channel and note index it was mapped to, if this input has its own profile.
+    fn map(
+        &self,
+        message: MidiMessage<'static>,
+    ) -> Option<(MidiMessage<'static>, Option<(usize, u64)>)> {
+        let message = self.filter_and_transpose(message)?;
+        let mapped = match (self.profile.lock().unwrap().as_ref(), &message) {
+            (
+                Some(profile),
+                MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _),
+            ) => Some(profile.mapping.map(channel.index(), *note as u8)?),
+            _ => None,
+        };
+        Some((message, mapped))
+    }
+
+    fn filter_and_transpose(&self, message: MidiMessage<'static>) -> Option<MidiMessage<'static>> {
+        if let (Some(filter), Some(channel)) = (self.channel.load(), message.channel()) {
+            if filter != channel {
+                return None;
+            }
+        }
+        let transpose = self.transpose.load();
+        match message {
+            MidiMessage::NoteOn(channel, note, velocity) => Some(MidiMessage::NoteOn(
+                channel,
+                note.step(transpose).ok()?,
+                velocity,
+            )),
+            MidiMessage::NoteOff(channel, note, velocity) => Some(MidiMessage::NoteOff(
+                channel,
+                note.step(transpose).ok()?,
+                velocity,
+            )),
+            _ => Some(message),
+        }
+    }
+}
+
+/// Maps the timestamps of a connection, which have an arbitrary start, onto `timer::now_us`.
+#[derive(Default)]
+struct Timestamps {
+    /// a timestamp and the time it was received
+    anchor: Option<(u64, u64)>,
+}
+
+impl Timestamps {
+    /// Keeps the spacing of messages that were delivered together, but never goes past `now`.
+    fn map(&mut self, timestamp: u64, now: u64) -> u64 {
+        if let Some((anchor_timestamp, anchor_now)) = self.anchor {
+            if let Some(time) = (timestamp + anchor_now).checked_sub(anchor_timestamp) {
+                // drifted too far, or the timestamps started over
+                if time <= now && now - time < 10_000 {
+                    return time;
+                }
+            }
+        }
+        self.anchor = Some((timestamp, now));
+        now
+    }
+}
+
+struct Input {
+    /// port to connect to, by name since the indices change when devices come and go.
+    /// None picks the first one that isn't used by another input.
+    wanted: Option<String>,
+    connection: Option<(MidiInputConnection<()>, String)>,
+    settings: Arc<InputSettings>,
+}
+
+impl Input {
+    fn new() -> Self {
+        Self {
+            wanted: None,
+            connection: None,
+            settings: Arc::new(InputSettings::default()),
+        }
+    }
+}
+
+/// What the ui needs to know about an input.
+pub struct InputInfo {
+    pub selected: Option<String>,
+    pub state: MidiState,
+    pub settings: Arc<InputSettings>,
+}
 
+/// Reads from any number of midi input ports and sends everything to the same channel.
 pub struct MidiReader {
     midi_events: MidiSender,
     timer: Timer,
-    port: Mutex<Option<(MidiInputConnection<()>, String)>>,
+    inputs: Mutex<Vec<Input>>,
+    /// available ports as of the last poll
+    ports: Mutex<Vec<String>>,
 }
 
 impl MidiReader {
     pub fn new(midi_events: MidiSender) -> Arc<Self> {
         let aself = Arc::new(Self {
             timer: Timer::new(),
-            port: Mutex::new(None),
+            inputs: Mutex::new(vec![Input::new()]),
+            ports: Mutex::new(vec![]),
             midi_events,
         });
-        aself.init();
+        aself.poll();
         aself
     }
 
-    fn init(self: &Arc<Self>) {
-        debug_assert!(self.port.lock().unwrap().is_none());
-        let r = (|| -> Result<()> {
-            let midi = MidiInput::new("wayfarer")?;
-            let ports = midi.ports();
-            if let Some(port) = ports.first() {
-                let name = midi.port_name(port)?;
-                let midi_events = self.midi_events.clone();
-                let connection = midi
-                    .connect(
-                        port,
-                        &name,
-                        move |_time_ms, message, _| match wmidi::MidiMessage::try_from(message) {
-                            Ok(message) => {
-                                if let Err(e) = midi_events.try_send(message.to_owned()) {
-                                    error!("error sending midi event {}", e);
-                                }
-                            }
-                            Err(e) => {
-                                error!("error parsing midi event {}", e);
-                            }
-                        },
-                        (),
-                    )
-                    .map_err(|e| anyhow!("{}", e))?;
-                *self.port.lock().unwrap() = Some((connection, name));
-            } else {
-                bail!("no midi so far");
-            }
-            Ok(())
-        })();
-        if let Err(e) = r {
+    /// Look for added and removed ports once a second and (re)connect as needed.
+    fn poll(self: &Arc<Self>) {
+        if let Err(e) = self.update() {
             warn!("error setting up midi: {}. retrying", e);
-            let weak_self = Arc::downgrade(self);
-            self.timer
-                .schedule_with_delay(&Duration::seconds(1), move || {
-                    if let Some(s) = weak_self.upgrade() {
-                        s.init();
+        }
+        let weak_self = Arc::downgrade(self);
+        self.timer
+            .schedule_with_delay(&Duration::seconds(1), move || {
+                if let Some(s) = weak_self.upgrade() {
+                    s.poll();
+                }
+            });
+    }
+
+    fn update(&self) -> Result<()> {
+        let names = {
+            let midi = MidiInput::new(CLIENT_NAME)?;
+            midi.ports()
+                .iter()
+                .filter_map(|port| midi.port_name(port).ok())
+                .filter(|name| !is_own_port(name))
+                .collect::<Vec<_>>()
+        };
+        *self.ports.lock().unwrap() = names.clone();
+
+        let mut inputs = self.inputs.lock().unwrap();
+        for input in inputs.iter_mut() {
+            if let Some((_, name)) = &input.connection {
+                let still_wanted = input.wanted.as_ref().is_none_or(|w| w == name);
+                let gone = name != VIRTUAL_INPUT && !names.contains(name);
+                if !still_wanted || gone {
+                    info!("disconnecting from {}", name);
+                    input.connection = None;
+                }
+            }
+        }
+        let mut used: Vec<String> = inputs
+            .iter()
+            .filter_map(|input| input.connection.as_ref().map(|(_, name)| name.clone()))
+            .collect();
+        let mut result = Ok(());
+        for input in inputs.iter_mut().filter(|input| input.connection.is_none()) {
+            if HAS_VIRTUAL_PORTS && input.wanted.as_deref() == Some(VIRTUAL_INPUT) {
+                match self.create_virtual(input.settings.clone()) {
+                    Ok(connection) => {
+                        info!("created virtual input {}", VIRTUAL_INPUT);
+                        input.connection = Some((connection, VIRTUAL_INPUT.to_string()));
+                    }
+                    Err(e) => result = Err(e),
+                }
+                continue;
+            }
+            let name = names.iter().find(|name| match &input.wanted {
+                Some(wanted) => *name == wanted,
+                None => !used.contains(name),
+            });
+            if let Some(name) = name {
+                match self.connect(name, input.settings.clone()) {
+                    Ok(connection) => {
+                        info!("connected to {}", name);
+                        used.push(name.clone());
+                        input.connection = Some((connection, name.clone()));
+                    }
+                    Err(e) => result = Err(e),
+                }
+            }
+        }
+        result
+    }
+
+    fn connect(&self, name: &str, settings: Arc<InputSettings>) -> Result<MidiInputConnection<()>> {
+        // connecting consumes the MidiInput, so each connection needs its own
+        let midi = MidiInput::new(CLIENT_NAME)?;
+        let port = match midi
+            .ports()
+            .into_iter()
+            .find(|port| midi.port_name(port).ok().as_deref() == Some(name))
+        {
+            Some(port) => port,
+            None => bail!("{} went away", name),
+        };
+        midi.connect(&port, name, self.forward(settings), ())
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_virtual(&self, settings: Arc<InputSettings>) -> Result<MidiInputConnection<()>> {
+        use midir::os::unix::VirtualInput;
+        MidiInput::new(CLIENT_NAME)?
+            .create_virtual(VIRTUAL_INPUT, self.forward(settings), ())
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn create_virtual(&self, _settings: Arc<InputSettings>) -> Result<MidiInputConnection<()>> {
+        bail!("virtual ports aren't supported on this platform")
+    }
+
+    /// Callback that passes the messages of a connection on to the synth.
+    fn forward(&self, settings: Arc<InputSettings>) -> impl FnMut(u64, &[u8], &mut ()) {
+        let midi_events = self.midi_events.clone();
+        let mut timestamps = Timestamps::default();
+        move |timestamp_us, message, _| match wmidi::MidiMessage::try_from(message) {
+            Ok(message) => {
+                if let Some((message, mapped)) = settings.map(message.to_owned()) {
+                    let time_us = timestamps.map(timestamp_us, timer::now_us());
+                    if let Err(e) = midi_events.try_send(InputEvent {
+                        time_us,
+                        message,
+                        mapped,
+                    }) {
+                        error!("error sending midi event {}", e);
                     }
-                });
+                }
+            }
+            Err(e) => {
+                error!("error parsing midi event {}", e);
+            }
         }
     }
 
-    pub fn get_name(&self) -> String {
-        self.port
+    /// Connect input `index` to the port called `name`, or to whichever free one comes first if None.
+    pub fn select(&self, index: usize, name: Option<String>) {
+        if let Some(input) = self.inputs.lock().unwrap().get_mut(index) {
+            input.wanted = name;
+        }
+        if let Err(e) = self.update() {
+            warn!("error setting up midi: {}", e);
+        }
+    }
+
+    pub fn add_input(&self) {
+        self.inputs.lock().unwrap().push(Input::new());
+        if let Err(e) = self.update() {
+            warn!("error setting up midi: {}", e);
+        }
+    }
+
+    /// Closes the connection of the input, if any.
+    pub fn remove_input(&self, index: usize) {
+        let mut inputs = self.inputs.lock().unwrap();
+        if index < inputs.len() {
+            inputs.remove(index);
+        }
+    }
+
+    pub fn get_inputs(&self) -> Vec<InputInfo> {
+        self.inputs
             .lock()
             .unwrap()
-            .as_ref()
-            .map(|(_, name)| name.clone())
-            .unwrap_or_else(|| "-".to_string())
+            .iter()
+            .map(|input| InputInfo {
+                selected: input.wanted.clone(),
+                state: match &input.connection {
+                    Some((_, name)) => MidiState::Connected(name.clone()),
+                    None => MidiState::Waiting(input.wanted.clone()),
+                },
+                settings: input.settings.clone(),
+            })
+            .collect()
+    }
+
+    pub fn get_ports(&self) -> Vec<String> {
+        self.ports.lock().unwrap().clone()
+    }
+}
+
+/// Sends the midi messages of the synth to an output port.
+pub struct MidiOut {
+    timer: Timer,
+    /// port to connect to, None to not send anything
+    wanted: Mutex<Option<String>>,
+    connection: Arc<Mutex<Option<(MidiOutputConnection, String)>>>,
+    ports: Mutex<Vec<String>>,
+}
+
+impl MidiOut {
+    pub fn new(events: channel::Receiver<OutputEvent>) -> Arc<Self> {
+        let aself = Arc::new(Self {
+            timer: Timer::new(),
+            wanted: Mutex::new(None),
+            connection: Arc::new(Mutex::new(None)),
+            ports: Mutex::new(vec![]),
+        });
+        Self::run(events, aself.connection.clone());
+        aself.poll();
+        aself
+    }
+
+    /// Send the events once playback reaches them, since the synth renders a whole buffer at a time.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn run(
+        events: channel::Receiver<OutputEvent>,
+        connection: Arc<Mutex<Option<(MidiOutputConnection, String)>>>,
+    ) {
+        use std::time::{Duration, Instant};
+        std::thread::spawn(move || {
+            // wall clock time of a frame, reset when the playback clock drifts too far from it
+            let mut anchor: Option<(Instant, u64, u32)> = None;
+            for event in events.iter() {
+                let now = Instant::now();
+                let due = anchor
+                    .filter(|&(_, frame, sample_rate)| {
+                        sample_rate == event.sample_rate && event.frame >= frame
+                    })
+                    .map(|(time, frame, sample_rate)| {
+                        time + Duration::from_secs_f64(
+                            (event.frame - frame) as f64 / f64::from(sample_rate),
+                        )
+                    });
+                match due {
+                    Some(due)
+                        if due < now + Duration::from_millis(100)
+                            && now < due + Duration::from_millis(20) =>
+                    {
+                        std::thread::sleep(due.saturating_duration_since(now))
+                    }
+                    _ => anchor = Some((now, event.frame, event.sample_rate)),
+                }
+                if let Some((connection, name)) = connection.lock().unwrap().as_mut() {
+                    if let Err(e) = connection.send(&event.message.to_vec()) {
+                        warn!("error sending midi to {}: {}", name, e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// No threads in the browser, so send whatever has arrived every few milliseconds.
+    #[cfg(target_arch = "wasm32")]
+    fn run(
+        events: channel::Receiver<OutputEvent>,
+        connection: Arc<Mutex<Option<(MidiOutputConnection, String)>>>,
+    ) {
+        if let Some((connection, name)) = connection.lock().unwrap().as_mut() {
+            for event in events.try_iter() {
+                if let Err(e) = connection.send(&event.message.to_vec()) {
+                    warn!("error sending midi to {}: {}", name, e);
+                }
+            }
+        } else {
+            events.try_iter().for_each(drop);
+        }
+        Timer::new().schedule_with_delay(&Duration::milliseconds(5), move || {
+            Self::run(events.clone(), connection.clone())
+        });
+    }
+
+    /// Look for the wanted port once a second and (re)connect as needed.
+    fn poll(self: &Arc<Self>) {
+        if let Err(e) = self.update() {
+            warn!("error setting up midi output: {}. retrying", e);
+        }
+        let weak_self = Arc::downgrade(self);
+        self.timer
+            .schedule_with_delay(&Duration::seconds(1), move || {
+                if let Some(s) = weak_self.upgrade() {
+                    s.poll();
+                }
+            });
+    }
+
+    fn update(&self) -> Result<()> {
+        let midi = MidiOutput::new(CLIENT_NAME)?;
+        let ports = midi.ports();
+        let names = ports
+            .iter()
+            .filter_map(|port| midi.port_name(port).ok())
+            .filter(|name| !is_own_port(name))
+            .collect::<Vec<_>>();
+        *self.ports.lock().unwrap() = names.clone();
+
+        let wanted = self.wanted.lock().unwrap().clone();
+        let mut connection = self.connection.lock().unwrap();
+        if let Some((_, name)) = &*connection {
+            let gone = name != VIRTUAL_OUTPUT && !names.contains(name);
+            if wanted.as_ref() != Some(name) || gone {
+                info!("disconnecting from output {}", name);
+                *connection = None;
+            }
+        }
+        if let (None, Some(wanted)) = (&*connection, wanted) {
+            if HAS_VIRTUAL_PORTS && wanted == VIRTUAL_OUTPUT {
+                *connection = Some((Self::create_virtual(midi)?, wanted));
+                info!("created virtual output {}", VIRTUAL_OUTPUT);
+            } else if let Some(port) = ports
+                .iter()
+                .find(|port| midi.port_name(port).ok().as_ref() == Some(&wanted))
+            {
+                *connection = Some((
+                    midi.connect(port, &wanted).map_err(|e| anyhow!("{}", e))?,
+                    wanted.clone(),
+                ));
+                info!("connected to output {}", wanted);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_virtual(midi: MidiOutput) -> Result<MidiOutputConnection> {
+        use midir::os::unix::VirtualOutput;
+        midi.create_virtual(VIRTUAL_OUTPUT)
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn create_virtual(_midi: MidiOutput) -> Result<MidiOutputConnection> {
+        bail!("virtual ports aren't supported on this platform")
+    }
+
+    /// Send to the port called `name`, or nowhere if None.
+    pub fn select(&self, name: Option<String>) {
+        *self.wanted.lock().unwrap() = name;
+        if let Err(e) = self.update() {
+            warn!("error setting up midi output: {}", e);
+        }
+    }
+
+    pub fn get_state(&self) -> (Option<String>, Option<MidiState>) {
+        let wanted = self.wanted.lock().unwrap().clone();
+        let state = match &*self.connection.lock().unwrap() {
+            Some((_, name)) => Some(MidiState::Connected(name.clone())),
+            None => wanted.clone().map(|w| MidiState::Waiting(Some(w))),
+        };
+        (wanted, state)
+    }
+
+    pub fn get_ports(&self) -> Vec<String> {
+        self.ports.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{InputSettings, Timestamps};
+    use crate::synth::note_mapping::{NoteMapping, Profile};
+    use std::sync::Arc;
+    use wmidi::{Channel, MidiMessage, Note, Velocity};
+
+    #[test]
+    fn filter_and_transpose() {
+        let settings = InputSettings::default();
+        let on = |channel, note| MidiMessage::NoteOn(channel, note, Velocity::MAX);
+        assert_eq!(
+            Some((on(Channel::Ch2, Note::C4), None)),
+            settings.map(on(Channel::Ch2, Note::C4))
+        );
+        settings.channel.store(Some(Channel::Ch10));
+        settings.transpose.store(-12);
+        assert_eq!(None, settings.map(on(Channel::Ch2, Note::C4)));
+        assert_eq!(
+            Some((on(Channel::Ch10, Note::C3), None)),
+            settings.map(on(Channel::Ch10, Note::C4))
+        );
+        // out of range notes are dropped
+        assert_eq!(None, settings.map(on(Channel::Ch10, Note::CMinus1)));
+    }
+
+    #[test]
+    fn own_profile() {
+        let settings = InputSettings::default();
+        *settings.profile.lock().unwrap() = Some(Arc::new(Profile {
+            mapping: NoteMapping::Chromatic { base_note: 60 },
+            ..Profile::builtin().remove(0)
+        }));
+        let on = |note| MidiMessage::NoteOn(Channel::Ch1, note, Velocity::MAX);
+        // c#5 is the second divisor of the second channel
+        assert_eq!(
+            Some((on(Note::CSharp5), Some((1, 1)))),
+            settings.map(on(Note::CSharp5))
+        );
+        // notes the profile doesn't map are dropped, everything else passes through
+        assert_eq!(None, settings.map(on(Note::C3)));
+        assert_eq!(
+            Some((MidiMessage::TimingClock, None)),
+            settings.map(MidiMessage::TimingClock)
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        let mut timestamps = Timestamps::default();
+        assert_eq!(1000, timestamps.map(5_000_000, 1000));
+        // delivered later together with the next one
+        assert_eq!(3000, timestamps.map(5_002_000, 4000));
+        assert_eq!(4000, timestamps.map(5_003_000, 4000));
+        // timestamps ahead of the clock start over
+        assert_eq!(4500, timestamps.map(5_004_000, 4500));
+        assert_eq!(5000, timestamps.map(5_004_500, 5000));
     }
 }
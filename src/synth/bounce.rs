@@ -0,0 +1,280 @@
+use super::{frames_per_step, Config, Synth, SynthPlayer, NUM_CHANNELS};
+use anyhow::{bail, Result};
+use crossbeam::channel;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::{io::Cursor, sync::Arc};
+use strum_macros::Display;
+
+pub const STEPS_PER_BAR: u64 = 16;
+pub const SAMPLE_RATES: [u32; 4] = [44100, 48000, 88200, 96000];
+const CHANNELS: usize = 2;
+const BLOCK_FRAMES: usize = 1024;
+
+#[derive(Copy, Clone, Display, enum_iterator::Sequence, PartialEq)]
+pub enum BitDepth {
+    #[strum(serialize = "16 bit")]
+    Int16,
+    #[strum(serialize = "24 bit")]
+    Int24,
+    #[strum(serialize = "32 bit float")]
+    Float32,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct BounceSettings {
+    pub bars: u32,
+    pub sample_rate: u32,
+    pub bit_depth: BitDepth,
+    /// render stems without channel volume, mute and master gain
+    pub dry_stems: bool,
+}
+
+impl Default for BounceSettings {
+    fn default() -> Self {
+        Self {
+            bars: 4,
+            sample_rate: 48000,
+            bit_depth: BitDepth::Int16,
+            dry_stems: false,
+        }
+    }
+}
+
+impl BounceSettings {
+    pub fn spec(&self) -> WavSpec {
+        let (bits_per_sample, sample_format) = match self.bit_depth {
+            BitDepth::Int16 => (16, SampleFormat::Int),
+            BitDepth::Int24 => (24, SampleFormat::Int),
+            BitDepth::Float32 => (32, SampleFormat::Float),
+        };
+        WavSpec {
+            channels: CHANNELS as u16,
+            sample_rate: self.sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+
+/// Writes one sample in the format given by `bit_depth`.
+pub fn write_sample<W>(writer: &mut WavWriter<W>, bit_depth: BitDepth, value: f32) -> Result<()>
+where
+    W: std::io::Write + std::io::Seek,
+{
+    let value = value.clamp(-1f32, 1f32);
+    match bit_depth {
+        BitDepth::Int16 => writer.write_sample((value * i16::MAX as f32) as i16)?,
+        BitDepth::Int24 => writer.write_sample((value * 8_388_607f32) as i32)?,
+        BitDepth::Float32 => writer.write_sample(value)?,
+    }
+    Ok(())
+}
+
+/// Wav file of a single channel
+pub struct Stem {
+    pub file_name: String,
+    pub data: Vec<u8>,
+}
+
+/// Sound names can come from user supplied kits, so keep them from escaping the directory they are written to.
+fn file_name_part(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+impl Config {
+    /// Number of frames in a bounce of `bars` bars at the current tempo.
+    pub fn bounce_length(&self, bars: u32, sample_rate: u32) -> u64 {
+        u64::from(frames_per_step(sample_rate, self.params.bpm.load()))
+            * STEPS_PER_BAR
+            * u64::from(bars)
+    }
+
+    /// Render the current state to a wav file.
+    /// Uses a separate synth starting from the first step, so the result doesn't depend on the live playback.
+    // TODO run this on a web worker to not block the main thread
+    pub fn generate_wav(&self, settings: &BounceSettings) -> Result<Vec<u8>> {
+        let (mut synth, frames) = self.bounce_synth(settings)?;
+
+        let mut buf = Cursor::new(Vec::new());
+        {
+            let mut writer = WavWriter::new(&mut buf, settings.spec())?;
+            let mut block = vec![0f32; BLOCK_FRAMES * CHANNELS];
+            let mut remaining = frames;
+            while remaining > 0 {
+                let n = remaining.min(BLOCK_FRAMES as u64) as usize;
+                let block = &mut block[..n * CHANNELS];
+                synth.play(settings.sample_rate, CHANNELS, block);
+                for &value in block.iter() {
+                    write_sample(&mut writer, settings.bit_depth, value)?;
+                }
+                remaining -= n as u64;
+            }
+            writer.finalize()?;
+        }
+        Ok(buf.into_inner())
+    }
+
+    /// Render each channel that has any notes to its own wav file.
+    /// All stems come from the same synth, so they line up with each other and with `generate_wav`.
+    pub fn generate_stems(&self, settings: &BounceSettings) -> Result<Vec<Stem>> {
+        let (mut synth, frames) = self.bounce_synth(settings)?;
+        let config = synth.get_config();
+        let muted = config.params.muted.load();
+        let channels: Vec<usize> = (0..NUM_CHANNELS)
+            .filter(|&c| {
+                config.get_triggered(c) != 0 && (settings.dry_stems || (muted >> c) & 1 == 0)
+            })
+            .collect();
+        if channels.is_empty() {
+            bail!("no channels to export");
+        }
+
+        let mut bufs: Vec<_> = channels.iter().map(|_| Cursor::new(Vec::new())).collect();
+        {
+            let mut writers = bufs
+                .iter_mut()
+                .map(|buf| WavWriter::new(buf, settings.spec()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut block = vec![[0f32; NUM_CHANNELS]; BLOCK_FRAMES];
+            let mut remaining = frames;
+            while remaining > 0 {
+                let n = remaining.min(BLOCK_FRAMES as u64) as usize;
+                let block = &mut block[..n];
+                synth.play_channels(settings.sample_rate, settings.dry_stems, block);
+                for frame in block.iter() {
+                    for (writer, &c) in writers.iter_mut().zip(channels.iter()) {
+                        for _ in 0..CHANNELS {
+                            write_sample(writer, settings.bit_depth, frame[c])?;
+                        }
+                    }
+                }
+                remaining -= n as u64;
+            }
+            for writer in writers {
+                writer.finalize()?;
+            }
+        }
+        Ok(channels
+            .iter()
+            .zip(bufs)
+            .map(|(&c, buf)| Stem {
+                file_name: format!(
+                    "{:02}_{}.wav",
+                    c + 1,
+                    file_name_part(&config.sound_name(config.params.channel_samples[c].load()))
+                ),
+                data: buf.into_inner(),
+            })
+            .collect())
+    }
+
+    fn bounce_synth(&self, settings: &BounceSettings) -> Result<(Synth, u64)> {
+        if settings.bars == 0 {
+            bail!("bounce length must be at least one bar");
+        }
+        let config = self.snapshot();
+        config.params.playing.store(true);
+        config.set_sample_rate(settings.sample_rate);
+        let frames = config.bounce_length(settings.bars, settings.sample_rate);
+        Ok((
+            Synth::with_config(channel::never(), Arc::new(config)),
+            frames,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{file_name_part, BitDepth, BounceSettings};
+    use crate::synth::Synth;
+    use crossbeam::channel;
+    use hound::WavReader;
+
+    #[test]
+    fn bounce_is_deterministic() {
+        let (_tx, rx) = channel::bounded(1);
+        let synth = Synth::new(rx);
+        let config = synth.get_config();
+        config.params.locked[0].store(0b1);
+        config.params.playing.store(false);
+        let settings = BounceSettings {
+            bars: 2,
+            sample_rate: 44100,
+            bit_depth: BitDepth::Float32,
+            dry_stems: false,
+        };
+        let wav = config.generate_wav(&settings).unwrap();
+        assert_eq!(wav, config.generate_wav(&settings).unwrap());
+
+        let reader = WavReader::new(wav.as_slice()).unwrap();
+        assert_eq!(reader.spec(), settings.spec());
+        assert_eq!(u64::from(reader.duration()), config.bounce_length(2, 44100));
+        let samples: Vec<f32> = reader.into_samples().map(|s| s.unwrap()).collect();
+        assert!(samples.iter().any(|&s| s != 0f32));
+    }
+
+    #[test]
+    fn stems_add_up_to_mix() {
+        let (_tx, rx) = channel::bounded(1);
+        let synth = Synth::new(rx);
+        let config = synth.get_config();
+        config.params.locked[0].store(0b1);
+        config.params.locked[3].store(0b110);
+        config.params.locked[5].store(0b1000);
+        config.params.muted.store(1 << 5);
+        config.params.channel_volumes_db[3].store(-3f32);
+        // keep the mix from clipping
+        config.params.gain_db.store(-6f32);
+        let settings = BounceSettings {
+            bars: 1,
+            sample_rate: 44100,
+            bit_depth: BitDepth::Float32,
+            dry_stems: false,
+        };
+        let read = |data: &[u8]| -> Vec<f32> {
+            WavReader::new(data)
+                .unwrap()
+                .into_samples()
+                .map(|s| s.unwrap())
+                .collect()
+        };
+        let mix = read(&config.generate_wav(&settings).unwrap());
+        let stems = config.generate_stems(&settings).unwrap();
+        // the muted channel is left out
+        assert_eq!(
+            vec!["01_Kick.wav", "04_HihatOpen.wav"],
+            stems
+                .iter()
+                .map(|s| s.file_name.as_str())
+                .collect::<Vec<_>>()
+        );
+        let stems: Vec<_> = stems.iter().map(|s| read(&s.data)).collect();
+        for (i, &m) in mix.iter().enumerate() {
+            let sum: f32 = stems.iter().map(|s| s[i]).sum();
+            assert!((m - sum).abs() < 1e-5);
+        }
+
+        let dry = config
+            .generate_stems(&BounceSettings {
+                dry_stems: true,
+                ..settings
+            })
+            .unwrap();
+        assert_eq!(3, dry.len());
+    }
+
+    #[test]
+    fn stem_names_stay_in_directory() {
+        assert_eq!("HihatOpen", file_name_part("HihatOpen"));
+        assert_eq!("___etc_passwd", file_name_part("../etc/passwd"));
+        assert_eq!("a_b_c-d", file_name_part("a\\b c-d"));
+    }
+}
@@ -0,0 +1,311 @@
+use super::sound_bank::{decode_wav, resample};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::{ops::RangeInclusive, path::Path};
+
+/// Json description of a kit. Sample files are relative to the manifest.
+#[derive(Deserialize)]
+struct KitManifest {
+    name: String,
+    samples: Vec<SampleManifest>,
+    /// default sound of each channel, from the first channel onwards
+    #[serde(default)]
+    channels: Vec<ChannelManifest>,
+}
+
+#[derive(Deserialize)]
+struct SampleManifest {
+    name: String,
+    /// a sample with a single layer, use either this or `layers`
+    #[serde(default)]
+    file: Option<String>,
+    #[serde(default)]
+    layers: Vec<LayerManifest>,
+    #[serde(default)]
+    rotation: Rotation,
+    /// in semitones
+    #[serde(default)]
+    tune: f32,
+    #[serde(default)]
+    gain_db: f32,
+}
+
+#[derive(Deserialize)]
+struct LayerManifest {
+    file: String,
+    /// lowest and highest velocity that plays this layer
+    #[serde(default = "full_velocity")]
+    velocity: [u8; 2],
+}
+
+fn full_velocity() -> [u8; 2] {
+    [0, 127]
+}
+
+/// How to choose between layers that cover the same velocity.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    #[default]
+    RoundRobin,
+    Random,
+}
+
+#[derive(Deserialize)]
+struct ChannelManifest {
+    sample: String,
+    #[serde(default)]
+    volume_db: f32,
+}
+
+/// A variation of a sample, mixed down to mono at its original sample rate.
+pub struct KitLayer {
+    data: Vec<f32>,
+    sample_rate: u32,
+    velocity: RangeInclusive<u8>,
+}
+
+/// A named sound of a kit, made up of one or more layers.
+pub struct KitSample {
+    pub name: String,
+    layers: Vec<KitLayer>,
+    rotation: Rotation,
+    /// in semitones
+    tune: f32,
+    gain_db: f32,
+}
+
+/// Default sound of a channel when switching to a kit.
+#[derive(Copy, Clone)]
+pub struct KitChannel {
+    /// index into `Kit::samples`
+    pub sample: u8,
+    pub volume_db: f32,
+}
+
+/// A set of named samples along with the channels they are assigned to by default.
+pub struct Kit {
+    pub name: String,
+    /// manifest the kit was loaded from, not available for the built-in kit or on the web
+    pub path: Option<String>,
+    pub samples: Vec<KitSample>,
+    pub channels: Vec<KitChannel>,
+}
+
+impl Kit {
+    /// Parse a kit manifest. `read_file` is called with the file name of each sample.
+    pub fn from_manifest<F>(json: &str, path: Option<String>, mut read_file: F) -> Result<Self>
+    where
+        F: FnMut(&str) -> Result<Vec<u8>>,
+    {
+        let manifest: KitManifest = serde_json::from_str(json)?;
+        if manifest.samples.is_empty() {
+            bail!("kit {} has no samples", manifest.name);
+        }
+        if manifest.samples.len() > usize::from(u8::MAX) + 1 {
+            bail!("kit {} has too many samples", manifest.name);
+        }
+        let samples = manifest
+            .samples
+            .into_iter()
+            .map(|sample| {
+                let layers = match (sample.file, sample.layers) {
+                    (Some(file), layers) if layers.is_empty() => vec![LayerManifest {
+                        file,
+                        velocity: full_velocity(),
+                    }],
+                    (None, layers) if !layers.is_empty() => layers,
+                    _ => bail!("sample {} needs either a file or layers", sample.name),
+                };
+                let layers = layers
+                    .into_iter()
+                    .map(|layer| {
+                        let (data, sample_rate) = decode_wav(&read_file(&layer.file)?)
+                            .with_context(|| format!("unable to load {}", layer.file))?;
+                        Ok(KitLayer {
+                            data,
+                            sample_rate,
+                            velocity: layer.velocity[0]..=layer.velocity[1],
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(KitSample {
+                    name: sample.name,
+                    layers,
+                    rotation: sample.rotation,
+                    tune: sample.tune,
+                    gain_db: sample.gain_db,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let channels = manifest
+            .channels
+            .iter()
+            .map(|channel| {
+                let sample = samples
+                    .iter()
+                    .position(|s| s.name == channel.sample)
+                    .ok_or_else(|| anyhow!("unknown sample {}", channel.sample))?;
+                Ok(KitChannel {
+                    sample: sample as u8,
+                    volume_db: channel.volume_db,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            name: manifest.name,
+            path,
+            samples,
+            channels,
+        })
+    }
+
+    /// Load a manifest and its samples from disk.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read {}", path.display()))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::from_manifest(&json, Some(path.display().to_string()), |file| {
+            let path = dir.join(file);
+            std::fs::read(&path).with_context(|| format!("unable to read {}", path.display()))
+        })
+    }
+
+    /// The samples that are embedded in the binary.
+    pub fn builtin() -> Self {
+        Self::from_manifest(
+            include_str!("../../samples/default_kit.json"),
+            None,
+            |file| {
+                Ok(match file {
+                    "hihat_closed.wav" => include_bytes!("../../samples/hihat_closed.wav").to_vec(),
+                    "hihat_open.wav" => include_bytes!("../../samples/hihat_open.wav").to_vec(),
+                    "snare.wav" => include_bytes!("../../samples/snare.wav").to_vec(),
+                    "cowbell.wav" => include_bytes!("../../samples/cowbell.wav").to_vec(),
+                    "kick.wav" => include_bytes!("../../samples/kick.wav").to_vec(),
+                    _ => bail!("no built-in file {}", file),
+                })
+            },
+        )
+        .unwrap()
+    }
+
+    pub fn find_sample(&self, name: &str) -> Option<u8> {
+        self.samples
+            .iter()
+            .position(|s| s.name == name)
+            .map(|i| i as u8)
+    }
+
+    /// Resample, tune and scale every layer of every sample for playback at `sample_rate`.
+    /// Layers that fail to resample are left empty.
+    pub fn resample_all(&self, sample_rate: u32) -> Vec<Vec<Vec<f32>>> {
+        self.samples
+            .iter()
+            .map(|sample| {
+                let gain = 10f32.powf(sample.gain_db / 20f32);
+                sample
+                    .layers
+                    .iter()
+                    .map(|layer| {
+                        // pretending the sample was recorded at a higher rate pitches it up
+                        let in_sample_rate = f64::from(layer.sample_rate)
+                            * 2f64.powf(f64::from(sample.tune) / 12f64);
+                        match resample(layer.data.clone(), in_sample_rate, sample_rate) {
+                            Ok(mut data) => {
+                                data.iter_mut().for_each(|v| *v *= gain);
+                                data
+                            }
+                            Err(e) => {
+                                log::error!("unable to resample {}: {}", sample.name, e);
+                                vec![]
+                            }
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl KitSample {
+    /// Pick the layer to play for a hit at `velocity`.
+    /// `hits` counts the previous hits, for round-robin, and `random` is used for random rotation.
+    /// Falls back to every layer if none covers the velocity.
+    pub fn choose_layer(&self, velocity: u8, hits: u64, random: u32) -> usize {
+        // runs on the audio thread, so count the candidates and then find the chosen one instead of collecting them
+        let covers = |layer: &KitLayer| layer.velocity.contains(&velocity);
+        let covered = self.layers.iter().filter(|&layer| covers(layer)).count();
+        let fallback = covered == 0;
+        let n = if fallback { self.layers.len() } else { covered } as u64;
+        let index = match self.rotation {
+            Rotation::RoundRobin => hits % n,
+            Rotation::Random => u64::from(random) % n,
+        };
+        self.layers
+            .iter()
+            .enumerate()
+            .filter(|&(_, layer)| fallback || covers(layer))
+            .nth(index as usize)
+            .map_or(0, |(i, _)| i)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Kit;
+    use anyhow::bail;
+
+    #[test]
+    fn builtin() {
+        let kit = Kit::builtin();
+        assert_eq!(5, kit.samples.len());
+        assert_eq!(Some(4), kit.find_sample("Kick"));
+        assert_eq!(4, kit.channels[0].sample);
+        let data = kit.resample_all(48000);
+        assert!(data.iter().flatten().all(|d| !d.is_empty()));
+    }
+
+    #[test]
+    fn layers() {
+        let kit = Kit::from_manifest(
+            r#"{"name": "a", "samples": [{"name": "hat", "layers": [
+                {"file": "soft.wav", "velocity": [0, 63]},
+                {"file": "hard1.wav", "velocity": [64, 127]},
+                {"file": "hard2.wav", "velocity": [64, 127]}
+            ]}]}"#,
+            None,
+            |_| Ok(include_bytes!("../../samples/hihat_closed.wav").to_vec()),
+        )
+        .unwrap();
+        let hat = &kit.samples[0];
+        assert_eq!(0, hat.choose_layer(10, 0, 0));
+        assert_eq!(0, hat.choose_layer(10, 1, 0));
+        let rotation: Vec<_> = (0..4).map(|hits| hat.choose_layer(100, hits, 0)).collect();
+        assert_eq!(vec![1, 2, 1, 2], rotation);
+        assert!(Kit::from_manifest(
+            r#"{"name": "a", "samples": [{"name": "k", "file": "k.wav", "layers": [{"file": "k.wav"}]}]}"#,
+            None,
+            |_| Ok(include_bytes!("../../samples/kick.wav").to_vec())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn invalid_manifests() {
+        let read = |_: &str| Ok(include_bytes!("../../samples/kick.wav").to_vec());
+        assert!(Kit::from_manifest(r#"{"name": "a", "samples": []}"#, None, read).is_err());
+        assert!(Kit::from_manifest(
+            r#"{"name": "a", "samples": [{"name": "k", "file": "k.wav"}], "channels": [{"sample": "x"}]}"#,
+            None,
+            read
+        )
+        .is_err());
+        assert!(Kit::from_manifest(
+            r#"{"name": "a", "samples": [{"name": "k", "file": "k.wav"}]}"#,
+            None,
+            |_| bail!("missing")
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use super::{note_mapping::NoteMapping, NOTES_PER_CHANNEL, NUM_CHANNELS};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use wmidi::MidiMessage;
+
+/// Velocities that light the pads of a controller in different colours.
+/// The defaults suit the palette of Launchpad style controllers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LedColors {
+    pub off: u8,
+    pub locked: u8,
+    pub selected: u8,
+    /// notes that flip on the current step
+    pub hit: u8,
+}
+
+impl Default for LedColors {
+    fn default() -> Self {
+        Self {
+            off: 0,
+            // green
+            locked: 21,
+            // yellow
+            selected: 13,
+            // red
+            hit: 5,
+        }
+    }
+}
+
+impl LedColors {
+    pub fn validate(&self) -> Result<()> {
+        for velocity in [self.off, self.locked, self.selected, self.hit] {
+            if velocity > 127 {
+                bail!("invalid led velocity {}, must be at most 127", velocity);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the controller is showing, so only changes need to be sent.
+#[derive(Clone)]
+pub struct Leds {
+    /// velocity sent to each note of each midi channel
+    sent: [[Option<u8>; 128]; 16],
+}
+
+impl Default for Leds {
+    fn default() -> Self {
+        Self {
+            sent: [[None; 128]; 16],
+        }
+    }
+}
+
+impl Leds {
+    /// Light the key of every note of every channel, or turn everything off if `mapping` is None.
+    /// `flipping` has the triggered notes that flip on the current step.
+    pub fn update(
+        &mut self,
+        mapping: Option<&NoteMapping>,
+        colors: &LedColors,
+        locked: &[u16; NUM_CHANNELS],
+        selected: &[u16; NUM_CHANNELS],
+        flipping: &[u16; NUM_CHANNELS],
+        mut send: impl FnMut(MidiMessage<'static>),
+    ) {
+        let mut wanted = [[None; 128]; 16];
+        if let Some(mapping) = mapping {
+            for channel in 0..NUM_CHANNELS {
+                for note in 0..NOTES_PER_CHANNEL {
+                    if let Some((midi_channel, key)) = mapping.key(channel, note) {
+                        let bit = 1 << note;
+                        let velocity = if flipping[channel] & bit != 0 {
+                            colors.hit
+                        } else if selected[channel] & bit != 0 {
+                            colors.selected
+                        } else if locked[channel] & bit != 0 {
+                            colors.locked
+                        } else {
+                            colors.off
+                        };
+                        wanted[usize::from(midi_channel)][usize::from(key)] = Some(velocity);
+                    }
+                }
+            }
+        }
+        for (midi_channel, (wanted, sent)) in wanted.iter().zip(self.sent.iter_mut()).enumerate() {
+            for (key, (&wanted, sent)) in wanted.iter().zip(sent.iter_mut()).enumerate() {
+                // keys that are no longer used are turned off
+                let velocity = match (wanted, *sent) {
+                    (Some(w), s) if s != Some(w) => w,
+                    (None, Some(s)) if s != colors.off => colors.off,
+                    _ => continue,
+                };
+                send(MidiMessage::NoteOn(
+                    wmidi::Channel::from_index(midi_channel as u8).unwrap(),
+                    wmidi::Note::from_u8_lossy(key as u8),
+                    wmidi::U7::from_u8_lossy(velocity),
+                ));
+                *sent = wanted;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LedColors, Leds};
+    use crate::synth::{note_mapping::NoteMapping, NUM_CHANNELS};
+    use wmidi::{Channel, MidiMessage, Note, U7};
+
+    #[test]
+    fn only_changes() {
+        let mapping = NoteMapping::ChannelPerDrum { base_note: 48 };
+        let colors = LedColors::default();
+        let mut leds = Leds::default();
+        let mut locked = [0; NUM_CHANNELS];
+        let selected = [0; NUM_CHANNELS];
+        let mut flipping = [0; NUM_CHANNELS];
+        let mut sent = vec![];
+        leds.update(
+            Some(&mapping),
+            &colors,
+            &locked,
+            &selected,
+            &flipping,
+            |m| sent.push(m),
+        );
+        // everything starts out off
+        assert_eq!(11 * 12, sent.len());
+
+        let on = |channel, note, velocity| {
+            MidiMessage::NoteOn(channel, note, U7::from_u8_lossy(velocity))
+        };
+        locked[1] = 0b10;
+        flipping[2] = 0b1;
+        sent.clear();
+        leds.update(
+            Some(&mapping),
+            &colors,
+            &locked,
+            &selected,
+            &flipping,
+            |m| sent.push(m),
+        );
+        assert_eq!(
+            vec![
+                on(Channel::Ch2, Note::CSharp3, colors.locked),
+                on(Channel::Ch3, Note::C3, colors.hit)
+            ],
+            sent
+        );
+
+        // a different mapping turns off the keys of the old one
+        sent.clear();
+        let mapping = NoteMapping::Chromatic { base_note: 0 };
+        leds.update(
+            Some(&mapping),
+            &colors,
+            &locked,
+            &selected,
+            &flipping,
+            |m| sent.push(m),
+        );
+        assert!(sent.contains(&on(Channel::Ch2, Note::CSharp3, colors.off)));
+        assert!(sent.contains(&on(Channel::Ch1, Note::CSharp0, colors.locked)));
+    }
+}
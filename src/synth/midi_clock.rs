@@ -0,0 +1,157 @@
+use strum_macros::Display;
+
+/// midi clock ticks per quarter note
+pub const TICKS_PER_BEAT: u64 = 24;
+/// steps are sixteenth notes
+pub const TICKS_PER_STEP: u64 = TICKS_PER_BEAT / 4;
+
+/// Where the tempo and step position come from.
+#[derive(Copy, Clone, Debug, Default, Display, enum_iterator::Sequence, PartialEq)]
+pub enum ClockSource {
+    /// the bpm setting
+    #[default]
+    #[strum(serialize = "internal")]
+    Internal,
+    /// midi clock, start, stop, continue and song position from the midi inputs
+    #[strum(serialize = "external")]
+    External,
+}
+
+/// Follows an incoming midi clock.
+/// Ticks are queued and played back spaced by the smoothed tick length, to even out jitter.
+#[derive(Clone, Default)]
+pub struct MidiClock {
+    running: bool,
+    /// index of the next tick to play, TICKS_PER_STEP per step
+    position: u64,
+    /// received but not yet played ticks
+    pending: u64,
+    /// frames left until the next pending tick may be played
+    countdown: f64,
+    /// frame of the first tick of the current measurement and the number of ticks since
+    window: Option<(u64, u64)>,
+    /// smoothed number of frames between ticks
+    tick_frames: Option<f64>,
+}
+
+impl MidiClock {
+    /// A tick received at `frame`.
+    pub fn tick(&mut self, frame: u64) {
+        match self.window {
+            // measure over a step to not be thrown off by single late ticks
+            Some((start, count)) if count == TICKS_PER_STEP => {
+                let estimate = (frame - start) as f64 / count as f64;
+                // start over if the clock was paused
+                let smoothed = match self.tick_frames {
+                    Some(previous) if estimate < previous * 4. => {
+                        previous + 0.2 * (estimate - previous)
+                    }
+                    _ => estimate,
+                };
+                self.tick_frames = Some(smoothed);
+                self.window = Some((frame, 1));
+            }
+            Some((start, count)) => self.window = Some((start, count + 1)),
+            None => self.window = Some((frame, 1)),
+        }
+        if self.running {
+            self.pending += 1;
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn start(&mut self) {
+        self.running = true;
+        self.position = 0;
+        self.pending = 0;
+        self.countdown = 0.;
+    }
+
+    pub fn stop(&mut self) {
+        self.running = false;
+        self.pending = 0;
+    }
+
+    pub fn resume(&mut self) {
+        self.running = true;
+        self.countdown = 0.;
+    }
+
+    /// `position` counts sixteenth notes, which are also the steps of the sequencer.
+    pub fn song_position(&mut self, position: u16) {
+        self.position = u64::from(position) * TICKS_PER_STEP;
+        self.pending = 0;
+    }
+
+    /// The followed tempo, once a few ticks have been received.
+    pub fn bpm(&self, sample_rate: u32) -> Option<f64> {
+        self.tick_frames
+            .filter(|&frames| frames > 0.)
+            .map(|frames| f64::from(sample_rate) * 60. / (frames * TICKS_PER_BEAT as f64))
+    }
+
+    /// Advance one frame. Returns the step that starts on this frame, if any.
+    pub fn next_frame(&mut self) -> Option<u64> {
+        self.countdown = (self.countdown - 1.).max(0.);
+        if self.pending == 0 || self.countdown > 0. {
+            return None;
+        }
+        let tick = self.position;
+        self.position += 1;
+        self.pending -= 1;
+        // catch up right away if we fall more than a step behind
+        if self.pending <= TICKS_PER_STEP {
+            self.countdown = self.tick_frames.unwrap_or(0.);
+        }
+        tick.is_multiple_of(TICKS_PER_STEP)
+            .then_some(tick / TICKS_PER_STEP)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MidiClock;
+
+    #[test]
+    fn follow() {
+        let mut clock = MidiClock::default();
+        // 120 bpm at 48kHz is 1000 frames per tick, received in bursts of 512 frames
+        let mut received = 0;
+        let mut steps = vec![];
+        clock.start();
+        for frame in 0..48000u64 {
+            if frame % 512 == 0 {
+                let due = frame / 1000 + 1;
+                for _ in received..due {
+                    clock.tick(frame);
+                }
+                received = due;
+            }
+            if let Some(step) = clock.next_frame() {
+                steps.push((frame, step));
+            }
+        }
+        let bpm = clock.bpm(48000).unwrap();
+        assert!((bpm - 120.).abs() < 2., "{}", bpm);
+        assert_eq!(
+            (0..8).collect::<Vec<_>>(),
+            steps.iter().map(|&(_, step)| step).collect::<Vec<_>>()
+        );
+        // evenly spaced despite the bursts
+        for pair in steps.windows(2).skip(2) {
+            let spacing = pair[1].0 - pair[0].0;
+            assert!((5800..6200).contains(&spacing), "{}", spacing);
+        }
+
+        clock.stop();
+        clock.song_position(16);
+        clock.tick(48000);
+        assert_eq!(None, clock.next_frame());
+        clock.resume();
+        clock.tick(49000);
+        assert_eq!(Some(16), clock.next_frame());
+    }
+}
@@ -0,0 +1,545 @@
+use super::{
+    bounce::STEPS_PER_BAR,
+    midi_writer::{self, MidiWriter},
+    Config, NOTES_PER_CHANNEL, NUM_CHANNELS,
+};
+use anyhow::{bail, Result};
+use array_init::array_init;
+use midly::{MetaMessage, TrackEvent, TrackEventKind};
+use num::Integer;
+use std::ops::Range;
+use strum_macros::Display;
+
+/// Longest export, in steps.
+const MAX_STEPS: u64 = 1 << 20;
+
+/// Notes of `NoteLayout::Keys`, the white keys from c4 to f5.
+const KEYS: [u8; NUM_CHANNELS] = [60, 62, 64, 65, 67, 69, 71, 72, 74, 76, 77];
+
+/// Resolutions to pick from, in ticks per beat.
+pub const PPQS: [u16; 6] = [4, 24, 48, 96, 480, 960];
+
+#[derive(Copy, Clone, Debug, Display, enum_iterator::Sequence, PartialEq)]
+pub enum LengthUnit {
+    #[strum(serialize = "bars")]
+    Bars,
+    #[strum(serialize = "steps")]
+    Steps,
+}
+
+/// Notes the channels are exported as.
+#[derive(Copy, Clone, Debug, Display, enum_iterator::Sequence, PartialEq)]
+pub enum NoteLayout {
+    /// the notes sent to the midi output, see `Config::output_note`
+    #[strum(serialize = "general midi drums")]
+    GeneralMidi,
+    /// a white key per channel on midi channel 1
+    #[strum(serialize = "white keys")]
+    Keys,
+}
+
+/// Length of the exported notes.
+/// Notes are cut short at the next hit of their channel, so they never overlap.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NoteLength {
+    Ticks(u32),
+    /// percentage of a step
+    Percent(u32),
+    /// until the next hit of the channel, or the end of the export
+    UntilNextHit,
+}
+
+#[derive(Copy, Clone, Debug, Display, enum_iterator::Sequence, PartialEq)]
+pub enum VelocitySource {
+    #[strum(serialize = "full")]
+    Full,
+    /// the velocity of the channel
+    #[strum(serialize = "channel velocity")]
+    Channel,
+    /// the velocity of the channel scaled by its volume
+    #[strum(serialize = "velocity and volume")]
+    Volume,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiExportSettings {
+    /// in `unit`
+    pub length: u32,
+    /// offset of the first exported step, in `unit`
+    pub start: u32,
+    pub unit: LengthUnit,
+    /// export a single period of the pattern instead of `length`
+    pub one_period: bool,
+    pub layout: NoteLayout,
+    /// a format 1 file with a tempo track and a named track per channel
+    pub multi_track: bool,
+    pub skip_muted: bool,
+    /// ticks per beat, a multiple of 4 so that steps are whole ticks
+    pub ppq: u16,
+    pub note_length: NoteLength,
+    pub velocity: VelocitySource,
+}
+
+impl Default for MidiExportSettings {
+    fn default() -> Self {
+        Self {
+            length: 64,
+            start: 0,
+            unit: LengthUnit::Bars,
+            one_period: false,
+            layout: NoteLayout::GeneralMidi,
+            multi_track: false,
+            skip_muted: false,
+            ppq: 96,
+            note_length: NoteLength::Percent(50),
+            velocity: VelocitySource::Channel,
+        }
+    }
+}
+
+impl MidiExportSettings {
+    fn to_steps(&self, value: u32) -> u64 {
+        match self.unit {
+            LengthUnit::Bars => u64::from(value) * STEPS_PER_BAR,
+            LengthUnit::Steps => u64::from(value),
+        }
+    }
+}
+
+/// How the hits of a channel are exported.
+#[derive(Copy, Clone)]
+struct ChannelNote {
+    midi_channel: u8,
+    key: u8,
+    velocity: u8,
+}
+
+impl ChannelNote {
+    fn on(&self, tick: u64) -> midi_writer::Event<'static> {
+        midi_writer::Event {
+            tick,
+            kind: TrackEventKind::Midi {
+                channel: self.midi_channel.into(),
+                message: midly::MidiMessage::NoteOn {
+                    key: self.key.into(),
+                    vel: self.velocity.into(),
+                },
+            },
+        }
+    }
+
+    /// Note off of a hit at `tick`, which lasts until `next` at most.
+    fn off(
+        &self,
+        settings: &MidiExportSettings,
+        tick: u64,
+        next: u64,
+        ticks_per_step: u64,
+    ) -> midi_writer::Event<'static> {
+        let length = match settings.note_length {
+            NoteLength::Ticks(ticks) => u64::from(ticks),
+            NoteLength::Percent(percent) => ticks_per_step * u64::from(percent) / 100,
+            NoteLength::UntilNextHit => next - tick,
+        };
+        midi_writer::Event {
+            tick: tick + length.clamp(1, next - tick),
+            kind: TrackEventKind::Midi {
+                channel: self.midi_channel.into(),
+                message: midly::MidiMessage::NoteOff {
+                    key: self.key.into(),
+                    vel: 127.into(),
+                },
+            },
+        }
+    }
+}
+
+impl Config {
+    /// Number of steps before the pattern repeats, the least common multiple of the triggered divisors.
+    pub fn pattern_period(&self) -> u64 {
+        (0..NUM_CHANNELS)
+            .flat_map(|c| {
+                let triggered = self.get_triggered(c);
+                (0..NOTES_PER_CHANNEL)
+                    .filter(move |n| triggered & (1 << n) != 0)
+                    .map(|n| n + 1)
+            })
+            .fold(1, |period, divisor| period.lcm(&divisor))
+    }
+
+    /// Steps exported with `settings`.
+    /// A single period starts at step 1 at the earliest, since step 0 is compared against the wrapped around
+    /// previous step, see `pattern::divisor_mask`, and would add hits that don't come back when looping.
+    pub fn export_steps(&self, settings: &MidiExportSettings) -> Result<Range<u64>> {
+        let start = settings.to_steps(settings.start);
+        let (start, length) = if settings.one_period {
+            (start.max(1), self.pattern_period())
+        } else {
+            (start, settings.to_steps(settings.length))
+        };
+        if length == 0 {
+            bail!("nothing to export");
+        }
+        if length > MAX_STEPS {
+            bail!("too long, {} steps, max is {}", length, MAX_STEPS);
+        }
+        Ok(start..start + length)
+    }
+
+    // TODO run this on a web worker to not block the main thread
+    pub fn generate_midi(&self, settings: &MidiExportSettings) -> Result<Vec<u8>> {
+        let steps = self.export_steps(settings)?;
+        if settings.ppq == 0 || !settings.ppq.is_multiple_of(4) {
+            bail!(
+                "invalid resolution {}, must be a multiple of 4",
+                settings.ppq
+            );
+        }
+        let notes: [ChannelNote; NUM_CHANNELS] = array_init(|c| {
+            let (midi_channel, key) = match settings.layout {
+                NoteLayout::GeneralMidi => {
+                    let note = self.output_note(c);
+                    (note.channel().index(), note.note)
+                }
+                NoteLayout::Keys => (0, KEYS[c]),
+            };
+            let velocity = self.params.channel_velocities[c].load();
+            let velocity = match settings.velocity {
+                VelocitySource::Full => 127,
+                VelocitySource::Channel => velocity,
+                VelocitySource::Volume => {
+                    let gain = 10f32.powf(self.params.channel_volumes_db[c].load() / 20f32);
+                    (f32::from(velocity) * gain).round().clamp(1f32, 127f32) as u8
+                }
+            };
+            ChannelNote {
+                midi_channel,
+                key,
+                velocity,
+            }
+        });
+        let muted = self.params.muted.load();
+        let channels = (0..NUM_CHANNELS)
+            .filter(|&c| !settings.skip_muted || (muted >> c) & 1 == 0)
+            .collect::<Vec<_>>();
+        let names = channels
+            .iter()
+            .map(|&c| {
+                format!(
+                    "{:02} {}",
+                    c + 1,
+                    self.sound_name(self.params.channel_samples[c].load())
+                )
+            })
+            .collect::<Vec<_>>();
+        let ticks_per_beat = settings.ppq;
+        let format = if settings.multi_track {
+            midly::Format::Parallel
+        } else {
+            midly::Format::SingleTrack
+        };
+        let mut smf = midly::Smf::new(midly::Header::new(
+            format,
+            midly::Timing::Metrical(ticks_per_beat.into()),
+        ));
+        let us_per_beat = (60 * 1_000_000 / self.params.bpm.load()).into();
+        let tempo = TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(us_per_beat)),
+        };
+        let end_of_track = TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        };
+        if settings.multi_track {
+            smf.tracks.push(vec![tempo, end_of_track]);
+            for (&c, name) in channels.iter().zip(&names) {
+                let mut track = vec![TrackEvent {
+                    delta: 0.into(),
+                    kind: TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())),
+                }];
+                self.write_notes(&mut track, &steps, settings, &[(c, notes[c])]);
+                track.push(end_of_track);
+                smf.tracks.push(track);
+            }
+        } else {
+            let mut track = vec![tempo];
+            let channels = channels.iter().map(|&c| (c, notes[c])).collect::<Vec<_>>();
+            self.write_notes(&mut track, &steps, settings, &channels);
+            track.push(end_of_track);
+            smf.tracks.push(track);
+        }
+        let mut buf = Vec::new();
+        smf.write(&mut buf).unwrap();
+        Ok(buf)
+    }
+
+    /// Add the hits of `steps` to `track`, for each channel along with its note.
+    fn write_notes(
+        &self,
+        track: &mut Vec<TrackEvent<'_>>,
+        steps: &Range<u64>,
+        settings: &MidiExportSettings,
+        channels: &[(usize, ChannelNote)],
+    ) {
+        let ticks_per_step = u64::from(settings.ppq / 4);
+        let end = (steps.end - steps.start) * ticks_per_step;
+        // the note off of a hit is added once the next hit of the channel is known
+        let mut previous_hits = vec![None; channels.len()];
+        let mut writer = MidiWriter::new(track);
+        for b in steps.clone() {
+            let tick = (b - steps.start) * ticks_per_step;
+            for (&(c, note), previous) in channels.iter().zip(&mut previous_hits) {
+                if self.get_beat(c, b) {
+                    if let Some(previous) = previous.replace(tick) {
+                        writer.add_event(note.off(settings, previous, tick, ticks_per_step));
+                    }
+                    writer.add_event(note.on(tick));
+                }
+            }
+            let pending = previous_hits
+                .iter()
+                .flatten()
+                .min()
+                .map_or(tick, |&t| t + 1);
+            writer.flush_until(pending.min(tick + 1));
+        }
+        for (&(_, note), previous) in channels.iter().zip(previous_hits) {
+            if let Some(previous) = previous {
+                writer.add_event(note.off(settings, previous, end, ticks_per_step));
+            }
+        }
+        writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LengthUnit, MidiExportSettings, NoteLayout, NoteLength, VelocitySource};
+    use crate::synth::Config;
+    use crate::synth::{midi_out::OutputNote, NUM_CHANNELS};
+
+    #[test]
+    fn length() {
+        let config = Config::new();
+        let mut settings = MidiExportSettings {
+            length: 2,
+            start: 1,
+            unit: LengthUnit::Bars,
+            layout: NoteLayout::Keys,
+            ppq: 4,
+            ..Default::default()
+        };
+        assert_eq!(16..48, config.export_steps(&settings).unwrap());
+        settings.unit = LengthUnit::Steps;
+        assert_eq!(1..3, config.export_steps(&settings).unwrap());
+
+        // divisors 4 and 6 on different channels repeat every 12 steps
+        config.params.locked[0].store(1 << 3);
+        config.params.locked[5].store(1 << 5);
+        settings.one_period = true;
+        assert_eq!(1..13, config.export_steps(&settings).unwrap());
+        settings.length = 0;
+        settings.one_period = false;
+        assert!(config.export_steps(&settings).is_err());
+
+        // the hits on steps 4 and 6 are at ticks 3 and 5
+        settings.length = 6;
+        assert_eq!(vec![(3, 0, 60), (5, 0, 69)], note_ons(&config, &settings));
+    }
+
+    #[test]
+    fn looped_period() {
+        let config = Config::new();
+        config.params.locked[0].store(1 << 3);
+        config.params.locked[5].store(1 << 5 | 1 << 2);
+        let mut settings = MidiExportSettings {
+            start: 0,
+            unit: LengthUnit::Steps,
+            one_period: true,
+            layout: NoteLayout::Keys,
+            ppq: 4,
+            ..Default::default()
+        };
+        assert_eq!(1..13, config.export_steps(&settings).unwrap());
+        let period = note_ons(&config, &settings);
+        assert!(!period.is_empty());
+        // the next period is the same, so the export loops seamlessly
+        settings.one_period = false;
+        settings.start = 13;
+        settings.length = 12;
+        assert_eq!(period, note_ons(&config, &settings));
+    }
+
+    /// Tick, midi channel and key of the note ons of an export.
+    fn note_ons(config: &Config, settings: &MidiExportSettings) -> Vec<(u32, u8, u8)> {
+        let data = config.generate_midi(settings).unwrap();
+        let smf = midly::Smf::parse(&data).unwrap();
+        let mut tick = 0;
+        let mut note_ons = vec![];
+        for event in &smf.tracks[0] {
+            tick += u32::from(event.delta);
+            if let midly::TrackEventKind::Midi {
+                channel,
+                message: midly::MidiMessage::NoteOn { key, .. },
+            } = event.kind
+            {
+                note_ons.push((tick, u8::from(channel), u8::from(key)));
+            }
+        }
+        note_ons
+    }
+
+    #[test]
+    fn general_midi() {
+        let config = Config::new();
+        // kick, snare and closed hihat on the first step
+        for c in 0..3 {
+            config.params.locked[c].store(1);
+        }
+        config.params.channel_output_notes[2].store(Some(OutputNote {
+            midi_channel: 2,
+            note: 44,
+        }));
+        let settings = MidiExportSettings {
+            length: 1,
+            unit: LengthUnit::Steps,
+            ..Default::default()
+        };
+        let mut notes = note_ons(&config, &settings);
+        notes.sort();
+        assert_eq!(vec![(0, 1, 44), (0, 9, 36), (0, 9, 38)], notes);
+        // unvalidated channels are clamped like on the midi output
+        config.params.channel_output_notes[2].store(Some(OutputNote {
+            midi_channel: 0,
+            note: 44,
+        }));
+        let mut notes = note_ons(&config, &settings);
+        notes.sort();
+        assert_eq!(vec![(0, 0, 44), (0, 9, 36), (0, 9, 38)], notes);
+    }
+
+    #[test]
+    fn multi_track() {
+        let config = Config::new();
+        config.params.locked[1].store(1 << 1);
+        config.params.muted.store(0b1);
+        let mut settings = MidiExportSettings {
+            length: 4,
+            unit: LengthUnit::Steps,
+            multi_track: true,
+            ..Default::default()
+        };
+        let data = config.generate_midi(&settings).unwrap();
+        let smf = midly::Smf::parse(&data).unwrap();
+        assert_eq!(midly::Format::Parallel, smf.header.format);
+        assert_eq!(1 + NUM_CHANNELS, smf.tracks.len());
+        assert!(matches!(
+            smf.tracks[0][0].kind,
+            midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(_))
+        ));
+        assert_eq!(
+            midly::TrackEventKind::Meta(midly::MetaMessage::TrackName(b"02 Snare")),
+            smf.tracks[2][0].kind
+        );
+        // the snare hits on steps 0 and 2 end up in its own track
+        let note_ons = smf
+            .tracks
+            .iter()
+            .map(|track| {
+                track
+                    .iter()
+                    .filter(|event| {
+                        matches!(
+                            event.kind,
+                            midly::TrackEventKind::Midi {
+                                message: midly::MidiMessage::NoteOn { .. },
+                                ..
+                            }
+                        )
+                    })
+                    .count()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(2, note_ons[2]);
+        assert_eq!(2, note_ons.iter().sum::<usize>());
+
+        settings.skip_muted = true;
+        let data = config.generate_midi(&settings).unwrap();
+        let smf = midly::Smf::parse(&data).unwrap();
+        assert_eq!(NUM_CHANNELS, smf.tracks.len());
+        assert_eq!(
+            midly::TrackEventKind::Meta(midly::MetaMessage::TrackName(b"02 Snare")),
+            smf.tracks[1][0].kind
+        );
+    }
+
+    #[test]
+    fn note_length_and_velocity() {
+        let config = Config::new();
+        // hits on steps 0 and 3
+        config.params.locked[0].store(1 << 2);
+        config.params.channel_velocities[0].store(100);
+        config.params.channel_volumes_db[0].store(-6.0206);
+        let mut settings = MidiExportSettings {
+            length: 6,
+            unit: LengthUnit::Steps,
+            ..Default::default()
+        };
+        // tick, note on and velocity of every note event
+        let events = |settings: &MidiExportSettings| {
+            let data = config.generate_midi(settings).unwrap();
+            let smf = midly::Smf::parse(&data).unwrap();
+            let mut tick = 0;
+            let mut events = vec![];
+            for event in &smf.tracks[0] {
+                tick += u32::from(event.delta);
+                match event.kind {
+                    midly::TrackEventKind::Midi {
+                        message: midly::MidiMessage::NoteOn { vel, .. },
+                        ..
+                    } => events.push((tick, true, u8::from(vel))),
+                    midly::TrackEventKind::Midi {
+                        message: midly::MidiMessage::NoteOff { .. },
+                        ..
+                    } => events.push((tick, false, 0)),
+                    _ => {}
+                }
+            }
+            events
+        };
+        assert_eq!(
+            vec![
+                (0, true, 100),
+                (12, false, 0),
+                (72, true, 100),
+                (84, false, 0)
+            ],
+            events(&settings)
+        );
+        settings.note_length = NoteLength::UntilNextHit;
+        settings.velocity = VelocitySource::Volume;
+        assert_eq!(
+            vec![
+                (0, true, 50),
+                (72, false, 0),
+                (72, true, 50),
+                (144, false, 0)
+            ],
+            events(&settings)
+        );
+        // too long notes are cut at the next hit
+        settings.note_length = NoteLength::Ticks(1000);
+        settings.velocity = VelocitySource::Full;
+        settings.ppq = 480;
+        assert_eq!(
+            vec![
+                (0, true, 127),
+                (360, false, 0),
+                (360, true, 127),
+                (720, false, 0)
+            ],
+            events(&settings)
+        );
+    }
+}
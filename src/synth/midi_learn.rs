@@ -0,0 +1,230 @@
+use super::{note_mapping::Profile, sound_bank::Sound, Config, UiEvent, NUM_CHANNELS};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt, sync::Arc};
+
+/// A parameter that can be controlled by a midi cc.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Bpm,
+    Gain,
+    Playing,
+    /// index of the channel, from 0
+    ChannelVolume(usize),
+    ChannelMute(usize),
+    ChannelSound(usize),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Bpm => write!(f, "bpm"),
+            Target::Gain => write!(f, "gain"),
+            Target::Playing => write!(f, "play/stop"),
+            Target::ChannelVolume(c) => write!(f, "channel {} volume", c + 1),
+            Target::ChannelMute(c) => write!(f, "channel {} mute", c + 1),
+            Target::ChannelSound(c) => write!(f, "channel {} sound", c + 1),
+        }
+    }
+}
+
+impl Target {
+    /// Values at cc 0 and 127 for a new binding.
+    /// Switches turn on at 64 and sounds are spread over the whole range, so they ignore this.
+    fn default_range(self) -> (f32, f32) {
+        match self {
+            Target::Bpm => (60f32, 180f32),
+            Target::Gain | Target::ChannelVolume(_) => (-30f32, 6f32),
+            Target::Playing | Target::ChannelMute(_) | Target::ChannelSound(_) => (0f32, 1f32),
+        }
+    }
+}
+
+/// Binds a cc to a parameter.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CcBinding {
+    /// 1 to 16
+    pub midi_channel: u8,
+    pub cc: u8,
+    pub target: Target,
+    /// value of the parameter at cc 0
+    pub min: f32,
+    /// value of the parameter at cc 127
+    pub max: f32,
+}
+
+impl CcBinding {
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=16).contains(&self.midi_channel) {
+            bail!(
+                "invalid midi channel {}, must be between 1 and 16",
+                self.midi_channel
+            );
+        }
+        if self.cc > 127 {
+            bail!("invalid cc {}, must be at most 127", self.cc);
+        }
+        match self.target {
+            Target::ChannelVolume(c) | Target::ChannelMute(c) | Target::ChannelSound(c)
+                if c >= NUM_CHANNELS =>
+            {
+                bail!(
+                    "invalid channel {}, must be between 1 and {}",
+                    c + 1,
+                    NUM_CHANNELS
+                );
+            }
+            _ => {}
+        }
+        if !self.min.is_finite() || !self.max.is_finite() {
+            bail!("cc range of {} must be finite", self.target);
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Apply a control change using the bindings of `profile`, or bind it if a parameter is waiting to be learned.
+    /// This runs on the audio thread, so new bindings and sound changes are returned for the ui to handle
+    /// using `handle_ui_event`.
+    pub(super) fn control_change(
+        &self,
+        profile: &Profile,
+        midi_channel: u8,
+        cc: u8,
+        value: u8,
+    ) -> Option<UiEvent> {
+        if let Some(target) = self.params.learning.take() {
+            let (min, max) = target.default_range();
+            return Some(UiEvent::Learned(CcBinding {
+                midi_channel: midi_channel + 1,
+                cc,
+                target,
+                min,
+                max,
+            }));
+        }
+        let mut deferred = None;
+        for binding in profile
+            .cc
+            .iter()
+            .filter(|b| b.midi_channel == midi_channel + 1 && b.cc == cc)
+        {
+            deferred = self.apply_cc(binding, value).or(deferred);
+        }
+        deferred
+    }
+
+    /// Add a binding to the active profile.
+    pub fn add_binding(&self, binding: CcBinding) {
+        let mut profile = (**self.params.profile.lock()).clone();
+        // a cc controls a single parameter, and a parameter is controlled by a single cc
+        profile.cc.retain(|b| {
+            b.target != binding.target
+                && (b.midi_channel, b.cc) != (binding.midi_channel, binding.cc)
+        });
+        profile.cc.push(binding);
+        // only the bindings changed, so this can't fail
+        let _ = self.set_profile(Arc::new(profile));
+    }
+
+    /// Changes that can't be made on the audio thread are returned instead.
+    fn apply_cc(&self, binding: &CcBinding, value: u8) -> Option<UiEvent> {
+        let scaled = binding.min + (binding.max - binding.min) * f32::from(value) / 127f32;
+        let on = value >= 64;
+        match binding.target {
+            Target::Bpm => self.params.bpm.store(scaled.round().max(1f32) as u32),
+            Target::Gain => self.params.gain_db.store(scaled),
+            Target::Playing => self.params.playing.store(on),
+            Target::ChannelVolume(c) => self.params.channel_volumes_db[c].store(scaled),
+            Target::ChannelMute(c) => {
+                let _ = self.params.muted.fetch_update(|muted| {
+                    Some(if on {
+                        muted | 1 << c
+                    } else {
+                        muted & !(1 << c)
+                    })
+                });
+            }
+            // looking up the sounds locks the kit
+            Target::ChannelSound(channel) => return Some(UiEvent::ChannelSound { channel, value }),
+        }
+        None
+    }
+
+    /// Switch `channel` to the sound at `value` of a `ChannelSound` cc, with the available sounds spread over the range.
+    pub(super) fn set_sound_from_cc(&self, channel: usize, value: u8) {
+        let sounds = self.available_sounds();
+        let sound = sounds[usize::from(value) * sounds.len() / 128];
+        if sound != self.params.channel_samples[channel].load() {
+            if let Sound::Voice(voice) = sound {
+                self.params.channel_voice_params[channel].store(voice.default_params());
+            }
+            self.set_channel_sound(channel, sound);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Target;
+    use crate::synth::{sound_bank::Sound, voice::Voice, Config, UiEvent};
+
+    #[test]
+    fn learn_and_apply() {
+        let config = Config::new();
+        let profile = config.params.profile.lock().clone();
+        // nothing is bound yet
+        assert!(config.control_change(&profile, 0, 7, 0).is_none());
+        // the binding comes back from the audio thread for the ui to add
+        let learn = |cc, target| {
+            config.params.learning.store(Some(target));
+            let profile = config.params.profile.lock().clone();
+            let learned = config.control_change(&profile, 0, cc, 100).unwrap();
+            assert!(matches!(learned, UiEvent::Learned(_)));
+            config.handle_ui_event(learned);
+        };
+
+        learn(7, Target::ChannelVolume(2));
+        assert_eq!(None, config.params.learning.load());
+        let profile = config.params.profile.lock().clone();
+        assert_eq!(1, profile.cc.len());
+
+        config.control_change(&profile, 0, 7, 127);
+        assert_eq!(6f32, config.params.channel_volumes_db[2].load());
+        config.control_change(&profile, 0, 7, 0);
+        assert_eq!(-30f32, config.params.channel_volumes_db[2].load());
+        // other midi channels are ignored
+        config.control_change(&profile, 1, 7, 127);
+        assert_eq!(-30f32, config.params.channel_volumes_db[2].load());
+
+        // learning the same cc again moves it to the new parameter
+        learn(7, Target::ChannelMute(1));
+        let profile = config.params.profile.lock().clone();
+        assert_eq!(1, profile.cc.len());
+        config.control_change(&profile, 0, 7, 127);
+        assert_eq!(0b10, config.params.muted.load());
+        config.control_change(&profile, 0, 7, 10);
+        assert_eq!(0, config.params.muted.load());
+
+        learn(8, Target::ChannelSound(0));
+        let profile = config.params.profile.lock().clone();
+        // sounds are changed by the ui
+        let sound_cc = |value| {
+            let event = config.control_change(&profile, 0, 8, value).unwrap();
+            assert_eq!(UiEvent::ChannelSound { channel: 0, value }, event);
+            config.handle_ui_event(event);
+        };
+        sound_cc(127);
+        assert_eq!(Sound::User, config.params.channel_samples[0].load());
+        sound_cc(0);
+        assert_eq!(Sound::Kit(0), config.params.channel_samples[0].load());
+        // the first voice comes right after the 5 samples of the builtin kit, out of 11 sounds
+        sound_cc(59);
+        assert_eq!(
+            Sound::Voice(Voice::AnalogKick),
+            config.params.channel_samples[0].load()
+        );
+    }
+}
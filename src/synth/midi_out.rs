@@ -0,0 +1,227 @@
+use super::{midi_clock::TICKS_PER_STEP, sound_bank::Sound, voice::Voice, Config, NUM_CHANNELS};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use wmidi::MidiMessage;
+
+/// General midi percussion notes for the channels, in the order of the built-in kit.
+const GM_DRUMS: [u8; NUM_CHANNELS] = [
+    // bass drum 1, acoustic snare, closed hihat, open hihat, cowbell
+    36, 38, 42, 46, 56,
+    // low tom, low mid tom, high tom, hand clap, crash cymbal 1, ride cymbal 1
+    45, 47, 50, 39, 49, 51,
+];
+
+/// General midi percussion note of a sample of the built-in kit.
+fn gm_sample_note(name: &str) -> Option<u8> {
+    Some(match name {
+        "Kick" => 36,
+        "Snare" => 38,
+        "HihatClosed" => 42,
+        "HihatOpen" => 46,
+        "Cowbell" => 56,
+        _ => return None,
+    })
+}
+
+/// Midi note played by the hits of a channel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputNote {
+    /// 1 to 16
+    pub midi_channel: u8,
+    pub note: u8,
+}
+
+impl OutputNote {
+    /// The general midi drum note of `channel`, on midi channel 10.
+    pub fn gm_drum(channel: usize) -> Self {
+        Self::gm(GM_DRUMS[channel])
+    }
+
+    fn gm(note: u8) -> Self {
+        Self {
+            midi_channel: 10,
+            note,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=16).contains(&self.midi_channel) {
+            bail!(
+                "invalid midi channel {}, must be between 1 and 16",
+                self.midi_channel
+            );
+        }
+        if self.note > 127 {
+            bail!("invalid note {}, must be at most 127", self.note);
+        }
+        Ok(())
+    }
+
+    pub fn note_on(&self, velocity: u8) -> MidiMessage<'static> {
+        MidiMessage::NoteOn(
+            self.channel(),
+            self.wmidi_note(),
+            wmidi::U7::from_u8_lossy(velocity),
+        )
+    }
+
+    pub fn note_off(&self) -> MidiMessage<'static> {
+        MidiMessage::NoteOff(self.channel(), self.wmidi_note(), wmidi::U7::MIN)
+    }
+
+    /// Out of range channels are clamped, they can only be stored in the params without `validate`.
+    pub fn channel(&self) -> wmidi::Channel {
+        wmidi::Channel::from_index(self.midi_channel.clamp(1, 16) - 1).unwrap()
+    }
+
+    fn wmidi_note(&self) -> wmidi::Note {
+        wmidi::Note::from_u8_lossy(self.note)
+    }
+}
+
+impl Config {
+    /// Note played by the hits of `channel`. Unless overridden it is the general midi drum note of its sound,
+    /// or of the channel if the sound has none.
+    pub fn output_note(&self, channel: usize) -> OutputNote {
+        self.params.channel_output_notes[channel]
+            .load()
+            .unwrap_or_else(|| {
+                let note = match self.params.channel_samples[channel].load() {
+                    Sound::Kit(slot) => self
+                        .params
+                        .kit
+                        .lock()
+                        .samples
+                        .get(usize::from(slot))
+                        .and_then(|sample| gm_sample_note(&sample.name)),
+                    Sound::Voice(voice) => Some(match voice {
+                        Voice::AnalogKick => 36,
+                        Voice::NoiseSnare => 38,
+                        Voice::MetalHat => 42,
+                        Voice::FmTom => 45,
+                        Voice::Clap => 39,
+                    }),
+                    Sound::User => None,
+                };
+                note.map_or_else(|| OutputNote::gm_drum(channel), OutputNote::gm)
+            })
+    }
+
+    /// Override the note of `channel`, or go back to the general midi drum note of its sound if None.
+    pub fn set_output_note(&self, channel: usize, note: Option<OutputNote>) {
+        self.params.channel_output_notes[channel].store(note);
+        self.params.output_notes[channel].store(self.output_note(channel));
+    }
+
+    /// Resolve the notes the synth sends, after the sounds, the overrides or the kit changed.
+    pub(super) fn update_output_notes(&self) {
+        for (channel, note) in self.params.output_notes.iter().enumerate() {
+            note.store(self.output_note(channel));
+        }
+    }
+}
+
+/// A midi message produced by the synth, to be sent once playback reaches `frame`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputEvent {
+    pub frame: u64,
+    pub sample_rate: u32,
+    pub message: MidiMessage<'static>,
+}
+
+/// Generates midi clock and transport messages from the playback clock.
+#[derive(Clone, Default)]
+pub struct ClockOut {
+    last_tick: Option<u64>,
+    playing: bool,
+}
+
+impl ClockOut {
+    /// Messages to send at frame `clock`. Steps start at multiples of `frames_per_step`.
+    pub fn next_frame(
+        &mut self,
+        clock: u64,
+        frames_per_step: u32,
+        playing: bool,
+        mut send: impl FnMut(MidiMessage<'static>),
+    ) {
+        let frames_per_step = u64::from(frames_per_step);
+        let step = clock / frames_per_step;
+        if playing != self.playing {
+            self.playing = playing;
+            if playing {
+                // playback doesn't restart from the beginning, so tell the receivers where it is
+                let position = wmidi::U14::try_from((step % 0x4000) as u16).unwrap();
+                send(MidiMessage::SongPositionPointer(position));
+                send(MidiMessage::Continue);
+            } else {
+                send(MidiMessage::Stop);
+            }
+        }
+        // keep the clock running while stopped so receivers know the tempo
+        let tick = clock * TICKS_PER_STEP / frames_per_step;
+        if self.last_tick != Some(tick) {
+            self.last_tick = Some(tick);
+            send(MidiMessage::TimingClock);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClockOut, OutputNote};
+    use crate::synth::{sound_bank::Sound, voice::Voice, Config};
+    use wmidi::MidiMessage;
+
+    #[test]
+    fn output_notes_follow_sounds() {
+        let config = Config::new();
+        let note = |c: usize| config.params.output_notes[c].load();
+        assert_eq!(OutputNote::gm_drum(0), note(0));
+        config.set_channel_sound(0, Sound::Voice(Voice::MetalHat));
+        assert_eq!(42, note(0).note);
+        let custom = OutputNote {
+            midi_channel: 2,
+            note: 60,
+        };
+        config.set_output_note(0, Some(custom));
+        assert_eq!(custom, note(0));
+        config.set_output_note(0, None);
+        assert_eq!(42, note(0).note);
+        // the built-in kit assigns the kick to the first channel
+        let kit = config.params.kit.lock().clone();
+        config.set_kit(kit);
+        assert_eq!(36, note(0).note);
+    }
+
+    #[test]
+    fn clock_and_transport() {
+        let mut out = ClockOut::default();
+        let mut messages = vec![];
+        for clock in 0..1200 {
+            out.next_frame(clock, 600, clock >= 600, |m| messages.push((clock, m)));
+        }
+        out.next_frame(1200, 600, false, |m| messages.push((1200, m)));
+        let ticks = messages
+            .iter()
+            .filter(|(_, m)| *m == MidiMessage::TimingClock)
+            .map(|(clock, _)| *clock)
+            .collect::<Vec<_>>();
+        assert_eq!((0..=12).map(|t| t * 100).collect::<Vec<_>>(), ticks);
+        let transport = messages
+            .into_iter()
+            .filter(|(_, m)| *m != MidiMessage::TimingClock)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    600,
+                    MidiMessage::SongPositionPointer(wmidi::U14::try_from(1).unwrap())
+                ),
+                (600, MidiMessage::Continue),
+                (1200, MidiMessage::Stop),
+            ],
+            transport
+        );
+    }
+}
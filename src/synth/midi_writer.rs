@@ -1,66 +1,126 @@
 use midly::{num::u28, TrackEvent, TrackEventKind};
-use std::{cmp::Ordering, collections::BinaryHeap, convert::TryFrom};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    convert::TryFrom,
+};
 
-#[derive(PartialEq, Eq)]
+/// Events are ordered by where they go in the track, see `Event::cmp`.
 pub struct Event<'a> {
     pub tick: u64,
     pub kind: TrackEventKind<'a>,
 }
 
+impl Event<'_> {
+    /// Order of events on the same tick.
+    /// Non midi events go first, so that tempo and names apply to the notes of their tick.
+    /// Note offs go before note ons, so that a retriggered note is ended before it starts again.
+    fn rank(&self) -> u8 {
+        match self.kind {
+            TrackEventKind::Midi { message, .. } => match message {
+                midly::MidiMessage::NoteOff { .. } => 1,
+                midly::MidiMessage::NoteOn { .. } => 3,
+                _ => 2,
+            },
+            _ => 0,
+        }
+    }
+
+    /// Channel, key and velocity of note events.
+    fn note(&self) -> Option<(u8, u8, u8)> {
+        match self.kind {
+            TrackEventKind::Midi {
+                channel,
+                message:
+                    midly::MidiMessage::NoteOn { key, vel } | midly::MidiMessage::NoteOff { key, vel },
+            } => Some((channel.as_int(), key.as_int(), vel.as_int())),
+            _ => None,
+        }
+    }
+
+    /// Tick, rank and note, with the loudest of the note ons of a key on the same tick first,
+    /// as it is the one that gets written.
+    fn order(&self) -> Order {
+        (
+            self.tick,
+            self.rank(),
+            self.note()
+                .map(|(channel, key, vel)| (channel, key, Reverse(vel))),
+        )
+    }
+}
+
+/// Tick, rank and note of an event.
+type Order = (u64, u8, Option<(u8, u8, Reverse<u8>)>);
+
 impl Ord for Event<'_> {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        // reverse order since we want smallest ticks first
-        // TODO this probably needs some love
-        other.tick.cmp(&self.tick).then_with(|| {
-            match self.kind {
-                TrackEventKind::Midi { channel, message } => match other.kind {
-                    TrackEventKind::Midi {
-                        channel: other_channel,
-                        message: other_message,
-                    } => other_channel.cmp(&channel).then_with(|| match message {
-                        // note off goes before everything else
-                        midly::MidiMessage::NoteOff { key, vel } => match other_message {
-                            midly::MidiMessage::NoteOff {
-                                key: other_key,
-                                vel: other_vel,
-                            } => key.cmp(&other_key).then_with(|| vel.cmp(&other_vel)),
-                            midly::MidiMessage::NoteOn { .. } => Ordering::Greater,
-                            _ => Ordering::Greater,
-                        },
-                        // note on goes last
-                        midly::MidiMessage::NoteOn { key, vel } => match other_message {
-                            midly::MidiMessage::NoteOff { .. } => Ordering::Less,
-                            midly::MidiMessage::NoteOn {
-                                key: other_key,
-                                vel: other_vel,
-                            } => key.cmp(&other_key).then_with(|| vel.cmp(&other_vel)),
-                            _ => Ordering::Less,
-                        },
-                        _ => match other_message {
-                            // noteoff goes before non note events
-                            midly::MidiMessage::NoteOff { .. } => Ordering::Less,
-                            _ => Ordering::Greater,
-                        },
-                    }),
-                    _ => Ordering::Equal,
-                },
-                // non midi events goes first
-                _ => Ordering::Greater,
-            }
-        })
+    /// Where the event goes in the track, by tick, `rank` and then note.
+    /// Events that compare equal, such as two meta events on the same tick, keep the order they were added in.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order().cmp(&other.order())
     }
 }
 
 impl PartialOrd for Event<'_> {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Consistent with `cmp`, so events with different contents can be equal.
+impl PartialEq for Event<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Event<'_> {}
+
+/// An event waiting to be written, ordered by `Event::cmp` and then by when it was added.
+struct Queued<'a> {
+    event: Event<'a>,
+    added: u64,
+}
+
+impl PartialEq for Queued<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued<'_> {}
+
+impl Ord for Queued<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.event
+            .cmp(&other.event)
+            .then_with(|| self.added.cmp(&other.added))
+    }
+}
+
+impl PartialOrd for Queued<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
+/// Sorts events into a track.
+/// Events are written once they are flushed, so they can be added a bit at a time without keeping the whole track around.
+/// Overlapping notes of the same key are turned into valid pairs of note on and note off:
+/// a note on of a sounding key ends the previous note, and the key is released by the last note off.
+/// Note ons of the same key on the same tick are merged into one note with the highest velocity.
 pub struct MidiWriter<'a, 'b> {
     track: &'a mut Vec<TrackEvent<'b>>,
-    heap: BinaryHeap<Event<'b>>,
+    heap: BinaryHeap<Reverse<Queued<'b>>>,
+    added: u64,
+    /// events before this have been written
+    flushed: u64,
+    /// tick of the last written event
     last_tick: u64,
+    /// number of note ons without a note off so far, for each channel and key
+    open: [[u32; 128]; 16],
+    /// tick of the last note on, for each channel and key
+    started: [[u64; 128]; 16],
 }
 
 impl<'a, 'b> MidiWriter<'a, 'b> {
@@ -68,25 +128,297 @@ impl<'a, 'b> MidiWriter<'a, 'b> {
         Self {
             track,
             heap: BinaryHeap::new(),
+            added: 0,
+            flushed: 0,
             last_tick: 0,
+            open: [[0; 128]; 16],
+            started: [[0; 128]; 16],
         }
     }
 
+    /// `event` must not be earlier than the tick of the previous flush.
     pub fn add_event(&mut self, event: Event<'b>) {
-        self.heap.push(event);
-        // TODO flush as much as possible?
+        debug_assert!(self.flushed <= event.tick);
+        self.heap.push(Reverse(Queued {
+            event,
+            added: self.added,
+        }));
+        self.added += 1;
+    }
+
+    /// Write the events that are earlier than `tick`.
+    /// Events added after this must not be earlier than `tick`.
+    pub fn flush_until(&mut self, tick: u64) {
+        while let Some(Reverse(queued)) = self.heap.peek() {
+            if queued.event.tick >= tick {
+                break;
+            }
+            let Reverse(Queued {
+                event: Event { tick, kind },
+                ..
+            }) = self.heap.pop().unwrap();
+            self.write(tick, kind);
+        }
+        self.flushed = self.flushed.max(tick);
     }
 
     pub fn flush(mut self) {
-        // TODO reserve in track
-        // TODO handle the case where a noteon is enqueued before the previous noteoff
-        while let Some(Event { tick, kind }) = self.heap.pop() {
-            debug_assert!(self.last_tick <= tick);
-            self.track.push(TrackEvent {
-                delta: u28::try_from(u32::try_from(tick - self.last_tick).unwrap()).unwrap(),
-                kind,
-            });
-            self.last_tick = tick;
+        self.flush_until(u64::MAX);
+    }
+
+    fn write(&mut self, tick: u64, kind: TrackEventKind<'b>) {
+        if let TrackEventKind::Midi { channel, message } = kind {
+            match message {
+                midly::MidiMessage::NoteOn { key, .. } => {
+                    let (channel_index, key_index) =
+                        (usize::from(channel.as_int()), usize::from(key.as_int()));
+                    let open = &mut self.open[channel_index][key_index];
+                    let started = &mut self.started[channel_index][key_index];
+                    *open += 1;
+                    if *open > 1 && *started == tick {
+                        // the louder note on of this tick has already been written
+                        return;
+                    }
+                    *started = tick;
+                    if *open > 1 {
+                        // end the sounding note before starting it again
+                        self.push(
+                            tick,
+                            TrackEventKind::Midi {
+                                channel,
+                                message: midly::MidiMessage::NoteOff { key, vel: 0.into() },
+                            },
+                        );
+                    }
+                }
+                midly::MidiMessage::NoteOff { key, .. } => {
+                    let open =
+                        &mut self.open[usize::from(channel.as_int())][usize::from(key.as_int())];
+                    match *open {
+                        // nothing to end
+                        0 => return,
+                        1 => *open = 0,
+                        // an overlapping note is still held
+                        _ => {
+                            *open -= 1;
+                            return;
+                        }
+                    }
+                }
+                _ => {}
+            }
         }
+        self.push(tick, kind);
+    }
+
+    fn push(&mut self, tick: u64, kind: TrackEventKind<'b>) {
+        debug_assert!(self.last_tick <= tick);
+        self.track.push(TrackEvent {
+            delta: u28::try_from(u32::try_from(tick - self.last_tick).unwrap()).unwrap(),
+            kind,
+        });
+        self.last_tick = tick;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, MidiWriter};
+    use midly::{MetaMessage, MidiMessage, TrackEventKind};
+    use std::cmp::Ordering;
+
+    fn note_on(tick: u64, channel: u8, key: u8) -> Event<'static> {
+        note_on_vel(tick, channel, key, 100)
+    }
+
+    fn note_on_vel(tick: u64, channel: u8, key: u8, vel: u8) -> Event<'static> {
+        Event {
+            tick,
+            kind: TrackEventKind::Midi {
+                channel: channel.into(),
+                message: MidiMessage::NoteOn {
+                    key: key.into(),
+                    vel: vel.into(),
+                },
+            },
+        }
+    }
+
+    fn note_off(tick: u64, channel: u8, key: u8) -> Event<'static> {
+        Event {
+            tick,
+            kind: TrackEventKind::Midi {
+                channel: channel.into(),
+                message: MidiMessage::NoteOff {
+                    key: key.into(),
+                    vel: 0.into(),
+                },
+            },
+        }
+    }
+
+    fn tempo(tick: u64) -> Event<'static> {
+        Event {
+            tick,
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(500_000.into())),
+        }
+    }
+
+    /// Absolute tick and kind of each event of a track.
+    fn write(events: Vec<Event<'static>>) -> Vec<(u64, TrackEventKind<'static>)> {
+        let mut track = vec![];
+        let mut writer = MidiWriter::new(&mut track);
+        for event in events {
+            writer.add_event(event);
+        }
+        writer.flush();
+        let mut tick = 0;
+        track
+            .into_iter()
+            .map(|event| {
+                tick += u64::from(u32::from(event.delta));
+                (tick, event.kind)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn order() {
+        // earlier ticks first
+        assert!(note_on(1, 0, 60) < note_off(2, 0, 60));
+        assert!(tempo(1) > note_on(0, 0, 60));
+        // on the same tick non midi events go first, then note offs, then other midi messages and note ons last
+        assert!(tempo(1) < note_off(1, 0, 60));
+        let control = || Event {
+            tick: 1,
+            kind: TrackEventKind::Midi {
+                channel: 0.into(),
+                message: MidiMessage::Controller {
+                    controller: 7.into(),
+                    value: 100.into(),
+                },
+            },
+        };
+        assert!(note_off(1, 3, 60) < control());
+        assert!(control() < note_on(1, 0, 60));
+        assert!(note_off(1, 9, 60) < note_on(1, 0, 36));
+        // notes are ordered by channel and key
+        assert!(note_on(1, 0, 60) < note_on(1, 1, 36));
+        assert!(note_on(1, 9, 36) < note_on(1, 9, 38));
+        assert_eq!(Ordering::Equal, note_on(1, 9, 36).cmp(&note_on(1, 9, 36)));
+        // different events can go in the same place, and are equal then
+        let name = Event {
+            tick: 1,
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(b"a")),
+        };
+        assert_eq!(Ordering::Equal, tempo(1).cmp(&name));
+        assert!(tempo(1) == name);
+        // louder notes of a key go first
+        assert!(note_on_vel(1, 9, 36, 120) < note_on_vel(1, 9, 36, 80));
+    }
+
+    #[test]
+    fn same_order_keeps_insertion() {
+        let events = write(vec![
+            Event {
+                tick: 0,
+                kind: TrackEventKind::Meta(MetaMessage::TrackName(b"b")),
+            },
+            tempo(0),
+        ]);
+        assert_eq!(
+            vec![
+                (0, TrackEventKind::Meta(MetaMessage::TrackName(b"b"))),
+                (0, tempo(0).kind),
+            ],
+            events
+        );
+    }
+
+    #[test]
+    fn overlapping_notes() {
+        let events = write(vec![
+            tempo(0),
+            note_on(0, 9, 42),
+            note_on(2, 9, 42),
+            note_off(4, 9, 42),
+            note_off(6, 9, 42),
+            // a different key isn't affected
+            note_on(3, 9, 36),
+            note_off(5, 9, 36),
+            // unmatched note offs are dropped
+            note_off(7, 9, 38),
+        ]);
+        assert_eq!(
+            vec![
+                tempo(0),
+                note_on(0, 9, 42),
+                // the first note is ended by the second one
+                note_off(2, 9, 42),
+                note_on(2, 9, 42),
+                note_on(3, 9, 36),
+                note_off(5, 9, 36),
+                // and the key is released by the last note off
+                note_off(6, 9, 42),
+            ]
+            .into_iter()
+            .map(|event| (event.tick, event.kind))
+            .collect::<Vec<_>>(),
+            events
+        );
+    }
+
+    #[test]
+    fn same_tick_notes() {
+        let events = write(vec![
+            note_on_vel(0, 9, 42, 80),
+            note_on_vel(0, 9, 42, 120),
+            note_off(2, 9, 42),
+            note_off(2, 9, 42),
+            // a later note on still ends the merged note
+            note_on_vel(4, 9, 42, 90),
+            note_on_vel(6, 9, 42, 100),
+            note_off(8, 9, 42),
+            note_off(8, 9, 42),
+        ]);
+        assert_eq!(
+            vec![
+                // one note with the highest velocity
+                note_on_vel(0, 9, 42, 120),
+                note_off(2, 9, 42),
+                note_on_vel(4, 9, 42, 90),
+                note_off(6, 9, 42),
+                note_on_vel(6, 9, 42, 100),
+                note_off(8, 9, 42),
+            ]
+            .into_iter()
+            .map(|event| (event.tick, event.kind))
+            .collect::<Vec<_>>(),
+            events
+        );
+    }
+
+    #[test]
+    fn streaming() {
+        let mut track = vec![];
+        let mut writer = MidiWriter::new(&mut track);
+        writer.add_event(note_on(0, 0, 60));
+        writer.add_event(note_off(4, 0, 60));
+        writer.add_event(note_on(2, 0, 62));
+        writer.flush_until(3);
+        writer.add_event(note_off(3, 0, 62));
+        writer.flush_until(4);
+        writer.add_event(note_on(4, 0, 64));
+        writer.add_event(note_off(5, 0, 64));
+        writer.flush();
+        let ticks = track
+            .iter()
+            .scan(0, |tick, event| {
+                *tick += u32::from(event.delta);
+                Some(*tick)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 2, 3, 4, 4, 5], ticks);
+        assert_eq!(note_off(4, 0, 60).kind, track[3].kind);
     }
 }
@@ -1,21 +1,63 @@
+pub mod bounce;
+pub mod kit;
+pub mod led_feedback;
+pub mod midi_clock;
+pub mod midi_export;
+pub mod midi_learn;
+pub mod midi_out;
 mod midi_writer;
+pub mod note_mapping;
+pub mod pattern;
+pub mod project;
 pub mod sound_bank;
+pub mod sysex;
+pub mod voice;
 use itertools::multizip;
-use midi_writer::MidiWriter;
-use std::sync::Arc;
+use kit::Kit;
+use led_feedback::Leds;
+use midi_clock::{ClockSource, MidiClock};
+use midi_learn::CcBinding;
+use midi_out::{ClockOut, OutputEvent, OutputNote};
+use note_mapping::{NoteMode, Profile};
+use parking_lot::Mutex;
+use pattern::PatternEngine;
+use sound_bank::{Bank, Sound, UserSample};
+use std::{collections::VecDeque, sync::Arc};
 
-use anyhow::Result;
 use array_init::array_init;
 use crossbeam::{atomic::AtomicCell, channel};
-use midly::{self, MetaMessage, TrackEvent, TrackEventKind};
+
+use crate::timer;
 use num::Integer;
-use static_assertions::const_assert;
 
-const NUM_CHANNELS: usize = 11;
+pub const NUM_CHANNELS: usize = 11;
 pub const PATTERN_LENGTH: u64 = 32;
 pub const NOTES_PER_CHANNEL: u64 = 12;
+/// midi messages that can wait to be applied, more than this arriving during a buffer are dropped
+const MAX_PENDING_MIDI: usize = 256;
+
+type MidiChannel = channel::Receiver<InputEvent>;
+
+/// A message from a midi input, with the time it was received according to `timer::now_us`.
+#[derive(Clone)]
+pub struct InputEvent {
+    pub time_us: u64,
+    pub message: wmidi::MidiMessage<'static>,
+    /// drum channel and note index of a note message, if its input maps notes with its own profile
+    pub mapped: Option<(usize, u64)>,
+}
 
-type MidiChannel = channel::Receiver<wmidi::MidiMessage<'static>>;
+/// Something from the midi inputs that the synth leaves to the ui, since handling it locks or allocates.
+/// See `Config::handle_ui_event`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UiEvent {
+    /// a learned binding to add to the active profile
+    Learned(CcBinding),
+    /// a cc bound to the sound of a channel
+    ChannelSound { channel: usize, value: u8 },
+    /// the data of a sysex message, without F0 and F7, see `sysex`
+    SysEx(Vec<wmidi::U7>),
+}
 
 // TODO handle params using messages instead?
 pub struct Params {
@@ -25,8 +67,39 @@ pub struct Params {
     pub playing: AtomicCell<bool>,
     // TODO assert that this is wide enough
     pub muted: AtomicCell<u64>,
-    pub channel_samples: [AtomicCell<sound_bank::Sample>; NUM_CHANNELS],
+    pub channel_samples: [AtomicCell<Sound>; NUM_CHANNELS],
+    /// only used by channels playing a synthesized voice
+    pub channel_voice_params: [AtomicCell<voice::VoiceParams>; NUM_CHANNELS],
     pub channel_volumes_db: [AtomicCell<f32>; NUM_CHANNELS],
+    /// velocity of every hit, picks the layer of kit samples and scales the output
+    pub channel_velocities: [AtomicCell<u8>; NUM_CHANNELS],
+    pub channel_note_modes: [AtomicCell<NoteMode>; NUM_CHANNELS],
+    /// set using `Config::set_kit`
+    pub kit: Mutex<Arc<Kit>>,
+    /// set using `Config::set_user_sample`
+    pub user_samples: [Mutex<Option<Arc<UserSample>>>; NUM_CHANNELS],
+    /// the sounds resampled for playback, set using `Config::set_sample_rate`
+    pub bank: Mutex<Option<Arc<Bank>>>,
+    /// bumped whenever `bank` changes
+    pub samples_generation: AtomicCell<u64>,
+    /// maps incoming midi notes, set using `Config::set_profile`
+    pub profile: Mutex<Arc<Profile>>,
+    /// bumped whenever `profile` changes
+    pub profile_generation: AtomicCell<u64>,
+    /// the next incoming cc gets bound to this
+    pub learning: AtomicCell<Option<midi_learn::Target>>,
+    /// with an external clock, bpm and playing follow the midi inputs
+    pub clock_source: AtomicCell<ClockSource>,
+    /// send midi clock and transport to the midi output, unless following an external clock
+    pub send_clock: AtomicCell<bool>,
+    /// send the hits of each channel as notes to the midi output
+    pub send_notes: AtomicCell<bool>,
+    /// overrides the note of each channel, set using `Config::set_output_note`
+    pub channel_output_notes: [AtomicCell<Option<OutputNote>>; NUM_CHANNELS],
+    /// `Config::output_note` of each channel, kept up to date so the audio thread doesn't need to lock the kit
+    pub output_notes: [AtomicCell<OutputNote>; NUM_CHANNELS],
+    /// show the notes of each channel on the pads of the controller, see `led_feedback`
+    pub send_leds: AtomicCell<bool>,
 }
 
 #[derive(Default)]
@@ -36,12 +109,15 @@ pub struct ChannelFeedback {
 
 pub struct Feedback {
     pub channels: [ChannelFeedback; NUM_CHANNELS],
+    /// number of midi messages dropped because too many arrived at once, see `MAX_PENDING_MIDI`
+    pub dropped_midi: AtomicCell<u64>,
 }
 
 impl Feedback {
     fn new() -> Self {
         Self {
             channels: Default::default(),
+            dropped_midi: 0.into(),
         }
     }
 }
@@ -49,6 +125,10 @@ impl Feedback {
 #[derive(Clone)]
 struct TimedClip {
     start_clock: u64,
+    voice: voice::VoiceState,
+    /// layer of the kit sample
+    layer: usize,
+    gain: f32,
 }
 
 pub struct Config {
@@ -57,136 +137,279 @@ pub struct Config {
     pub selected: [AtomicCell<u16>; NUM_CHANNELS],
 }
 
+fn frames_per_step(sample_rate: u32, bpm: u32) -> u32 {
+    sample_rate * 60 / (bpm * 4)
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Config {
-    fn get_beat(&self, channel: usize, beat: u64) -> bool {
-        // TODO don't load here. make copy of config to use to generate a pattern or midi?
-        let selected = self.selected[channel].load();
-        let locked = self.params.locked[channel].load();
-        let triggered = selected | locked;
-        let f = |b| {
-            let mut a = false;
-            for n in 0..NOTES_PER_CHANNEL {
-                if triggered & (1 << n) != 0 {
-                    // TODO use different divisors. n2, fib?
-                    let div = n + 1;
-                    let c = (b / div) & 1 == 0;
-                    a = a != c;
-                }
-            }
-            a
+    /// Uses the built-in kit and its channel assignments.
+    pub fn new() -> Self {
+        let kit = Arc::new(Kit::builtin());
+        let config = Self {
+            params: Params {
+                gain_db: 0f32.into(),
+                locked: Default::default(),
+                bpm: 120.into(),
+                playing: true.into(),
+                muted: 0.into(),
+                channel_samples: array_init(|_| AtomicCell::new(Sound::Kit(0))),
+                channel_voice_params: array_init(|_| {
+                    AtomicCell::new(voice::Voice::AnalogKick.default_params())
+                }),
+                channel_volumes_db: array_init(|_| AtomicCell::new(0f32)),
+                channel_velocities: array_init(|_| AtomicCell::new(127)),
+                channel_note_modes: Default::default(),
+                kit: Mutex::new(kit.clone()),
+                user_samples: Default::default(),
+                bank: Mutex::new(None),
+                samples_generation: 0.into(),
+                profile: Mutex::new(Arc::new(Profile::builtin().remove(0))),
+                profile_generation: 0.into(),
+                learning: AtomicCell::new(None),
+                clock_source: Default::default(),
+                send_clock: false.into(),
+                send_notes: false.into(),
+                channel_output_notes: Default::default(),
+                output_notes: array_init(|c| AtomicCell::new(OutputNote::gm_drum(c))),
+                send_leds: false.into(),
+            },
+            feedback: Feedback::new(),
+            selected: Default::default(),
         };
-        f(beat) != f(beat.wrapping_sub(1))
-    }
-
-    // TODO run this on a web worker to not block the main thread
-    pub fn generate_midi(&self) -> Result<Vec<u8>> {
-        // TODO proper tempo
-        let ticks_per_beat = 4;
-        let mut smf = midly::Smf::new(midly::Header::new(
-            midly::Format::SingleTrack,
-            midly::Timing::Metrical(ticks_per_beat.into()),
-        ));
-        let mut track = vec![];
-        let us_per_beat = (60 * 1_000_000 / self.params.bpm.load()).into();
-        track.push(TrackEvent {
-            delta: 0.into(),
-            kind: TrackEventKind::Meta(MetaMessage::Tempo(us_per_beat)),
-        });
-        {
-            let mut writer = MidiWriter::new(&mut track);
-            // TODO some other length
-            for b in 0..1024 {
-                for c in 0..NUM_CHANNELS {
-                    if self.get_beat(c, b) {
-                        let key = match c {
-                            // c4
-                            0 => 60,
-                            1 => 62,
-                            2 => 64,
-                            3 => 65,
-                            4 => 67,
-                            5 => 69,
-                            6 => 71,
-                            // c5
-                            7 => 72,
-                            8 => 74,
-                            9 => 76,
-                            10 => 77,
-                            _ => panic!("unexpected channel {}", c),
-                        }
-                        .into();
-                        writer.add_event(midi_writer::Event {
-                            tick: b,
-                            kind: TrackEventKind::Midi {
-                                channel: 0.into(),
-                                message: midly::MidiMessage::NoteOn {
-                                    vel: 127.into(),
-                                    key,
-                                },
-                            },
-                        });
-                        // TODO some other note length?
-                        writer.add_event(midi_writer::Event {
-                            tick: b + 1,
-                            kind: TrackEventKind::Midi {
-                                channel: 0.into(),
-                                message: midly::MidiMessage::NoteOff {
-                                    vel: 127.into(),
-                                    key,
-                                },
-                            },
-                        });
-                    }
+        config.set_kit(kit);
+        config
+    }
+
+    /// Copy of the current state that is detached from the running synth.
+    pub fn snapshot(&self) -> Self {
+        Self {
+            params: Params {
+                gain_db: self.params.gain_db.load().into(),
+                locked: array_init(|c| self.params.locked[c].load().into()),
+                bpm: self.params.bpm.load().into(),
+                playing: self.params.playing.load().into(),
+                muted: self.params.muted.load().into(),
+                channel_samples: array_init(|c| self.params.channel_samples[c].load().into()),
+                channel_voice_params: array_init(|c| {
+                    self.params.channel_voice_params[c].load().into()
+                }),
+                channel_volumes_db: array_init(|c| self.params.channel_volumes_db[c].load().into()),
+                channel_velocities: array_init(|c| self.params.channel_velocities[c].load().into()),
+                channel_note_modes: array_init(|c| self.params.channel_note_modes[c].load().into()),
+                kit: Mutex::new(self.params.kit.lock().clone()),
+                user_samples: array_init(|c| {
+                    Mutex::new(self.params.user_samples[c].lock().clone())
+                }),
+                bank: Mutex::new(self.params.bank.lock().clone()),
+                samples_generation: self.params.samples_generation.load().into(),
+                profile: Mutex::new(self.params.profile.lock().clone()),
+                profile_generation: self.params.profile_generation.load().into(),
+                learning: AtomicCell::new(None),
+                // nothing drives an external clock of a detached copy
+                clock_source: Default::default(),
+                send_clock: self.params.send_clock.load().into(),
+                send_notes: self.params.send_notes.load().into(),
+                channel_output_notes: array_init(|c| {
+                    self.params.channel_output_notes[c].load().into()
+                }),
+                output_notes: array_init(|c| self.params.output_notes[c].load().into()),
+                send_leds: self.params.send_leds.load().into(),
+            },
+            feedback: Feedback::new(),
+            selected: array_init(|c| self.selected[c].load().into()),
+        }
+    }
+
+    /// Resample the kit and the user samples for playback at `sample_rate`, unless they already are.
+    /// Needs to be called before playing at a new rate, the synth doesn't resample on the audio thread.
+    pub fn set_sample_rate(&self, sample_rate: u32) {
+        let mut bank = self.params.bank.lock();
+        if bank.as_ref().map(|bank| bank.get_sample_rate()) != Some(sample_rate) {
+            let mut new_bank = Bank::new(self.params.kit.lock().clone(), sample_rate);
+            for (channel, sample) in self.params.user_samples.iter().enumerate() {
+                new_bank.update_user_sample(channel, sample.lock().clone());
+            }
+            *bank = Some(Arc::new(new_bank));
+            self.params.samples_generation.fetch_add(1);
+        }
+    }
+
+    /// Modify a copy of the resampled sounds, the synth swaps it in on its next buffer.
+    fn update_bank<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Bank),
+    {
+        if let Some(bank) = self.params.bank.lock().as_mut() {
+            f(Arc::make_mut(bank));
+        }
+        self.params.samples_generation.fetch_add(1);
+    }
+
+    /// Load a sample into a channel and make the channel play it.
+    pub fn set_user_sample(&self, channel: usize, sample: Option<Arc<UserSample>>) {
+        *self.params.user_samples[channel].lock() = sample.clone();
+        self.update_bank(|bank| bank.update_user_sample(channel, sample));
+        self.set_channel_sound(channel, Sound::User);
+    }
+
+    /// Make `channel` play `sound`.
+    pub fn set_channel_sound(&self, channel: usize, sound: Sound) {
+        self.params.channel_samples[channel].store(sound);
+        self.params.output_notes[channel].store(self.output_note(channel));
+    }
+
+    /// Switch to `kit` and apply its channel assignments.
+    /// Channels without an assignment that play a sample the kit doesn't have fall back to its first sample.
+    pub fn set_kit(&self, kit: Arc<Kit>) {
+        for (c, sound) in self.params.channel_samples.iter().enumerate() {
+            if let Some(channel) = kit.channels.get(c) {
+                sound.store(Sound::Kit(channel.sample));
+                self.params.channel_volumes_db[c].store(channel.volume_db);
+            } else if let Sound::Kit(slot) = sound.load() {
+                if usize::from(slot) >= kit.samples.len() {
+                    sound.store(Sound::Kit(0));
                 }
             }
-            writer.flush();
         }
-        track.push(TrackEvent {
-            delta: 0.into(),
-            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
-        });
-        smf.tracks.push(track);
-        let mut buf = Vec::new();
-        smf.write(&mut buf).unwrap();
-        Ok(buf)
+        *self.params.kit.lock() = kit.clone();
+        self.update_bank(|bank| bank.update_kit(kit));
+        self.update_output_notes();
+    }
+
+    /// Name of `sound` as shown in the ui and stored in projects.
+    pub fn sound_name(&self, sound: Sound) -> String {
+        match sound {
+            Sound::Kit(slot) => self
+                .params
+                .kit
+                .lock()
+                .samples
+                .get(usize::from(slot))
+                .map(|s| s.name.clone())
+                .unwrap_or_else(|| "Missing".into()),
+            Sound::Voice(voice) => voice.to_string(),
+            Sound::User => "User".into(),
+        }
+    }
+
+    /// Samples of the current kit take precedence over voices with the same name.
+    pub fn find_sound(&self, name: &str) -> Option<Sound> {
+        self.available_sounds()
+            .into_iter()
+            .find(|&sound| self.sound_name(sound) == name)
+    }
+
+    /// Every sound a channel can play with the current kit.
+    pub fn available_sounds(&self) -> Vec<Sound> {
+        let num_samples = self.params.kit.lock().samples.len();
+        (0..num_samples)
+            .map(|slot| Sound::Kit(slot as u8))
+            .chain(enum_iterator::all::<voice::Voice>().map(Sound::Voice))
+            .chain(std::iter::once(Sound::User))
+            .collect()
+    }
+
+    /// Handle an event the synth sent to `Synth::set_ui_output`, off the audio thread.
+    /// Returns a reply for the synth to send to the midi output, see `Synth::set_ui_midi`.
+    pub fn handle_ui_event(&self, event: UiEvent) -> Option<wmidi::MidiMessage<'static>> {
+        match event {
+            UiEvent::Learned(binding) => self.add_binding(binding),
+            UiEvent::ChannelSound { channel, value } => self.set_sound_from_cc(channel, value),
+            UiEvent::SysEx(data) => return self.handle_sysex(&data),
+        }
+        None
+    }
+
+    fn get_triggered(&self, channel: usize) -> u16 {
+        self.selected[channel].load() | self.params.locked[channel].load()
+    }
+
+    fn get_beat(&self, channel: usize, beat: u64) -> bool {
+        // TODO don't load here. make copy of config to use to generate a pattern or midi?
+        pattern::is_hit(self.get_triggered(channel), pattern::divisor_mask(beat))
     }
 }
 
 #[derive(Clone)]
 pub struct Synth {
-    sound_bank: Option<sound_bank::Bank>,
+    /// swapped in from `Params::bank`
+    sound_bank: Option<Arc<Bank>>,
+    /// of the buffer being played
+    sample_rate: u32,
 
     clock: u64,
     midi_events: MidiChannel,
+    /// received midi messages and the frames to apply them at, never grown past `MAX_PENDING_MIDI`
+    pending_midi: VecDeque<(u64, InputEvent)>,
+    /// when the previous buffer was prepared
+    last_prepare_us: Option<u64>,
+    /// swapped in from `Params::profile`
+    profile: Arc<Profile>,
+    profile_generation: u64,
+    midi_output: Option<channel::Sender<OutputEvent>>,
 
     config: Arc<Config>,
     playing: [Option<TimedClip>; NUM_CHANNELS],
+    /// number of hits so far, for round-robin layers
+    hits: [u64; NUM_CHANNELS],
+    /// notes currently pressed on midi inputs
+    held: [u16; NUM_CHANNELS],
+    pattern_engine: PatternEngine,
+    midi_clock: MidiClock,
+    clock_out: ClockOut,
+    /// notes sent to the midi output that haven't been released yet
+    notes_on: [Option<OutputNote>; NUM_CHANNELS],
+    feedback_output: Option<channel::Sender<OutputEvent>>,
+    /// events for the ui to handle, see `UiEvent`
+    ui_output: Option<channel::Sender<UiEvent>>,
+    /// messages from the ui to send to the midi output, such as state dumps
+    ui_midi: Option<channel::Receiver<wmidi::MidiMessage<'static>>>,
+    leds: Leds,
+    /// triggered notes that flip on the current step
+    flipping: [u16; NUM_CHANNELS],
+    samples_generation: Option<u64>,
 
     lowpass: [f32; NUM_CHANNELS],
 }
 
 impl Synth {
     pub fn new(midi_events: MidiChannel) -> Self {
+        Self::with_config(midi_events, Arc::new(Config::new()))
+    }
+
+    pub fn with_config(midi_events: MidiChannel, config: Arc<Config>) -> Self {
+        let profile = config.params.profile.lock().clone();
+        let profile_generation = config.params.profile_generation.load();
         Self {
             sound_bank: None,
+            sample_rate: 0,
             clock: 0,
             midi_events,
-            config: Arc::new(Config {
-                params: Params {
-                    gain_db: 0f32.into(),
-                    locked: Default::default(),
-                    bpm: 120.into(),
-                    playing: true.into(),
-                    muted: 0.into(),
-                    channel_samples: array_init(|_| {
-                        AtomicCell::new(sound_bank::Sample::HihatClosed)
-                    }),
-                    channel_volumes_db: array_init(|_| AtomicCell::new(0f32)),
-                },
-                feedback: Feedback::new(),
-                selected: Default::default(),
-            }),
+            pending_midi: VecDeque::with_capacity(MAX_PENDING_MIDI),
+            last_prepare_us: None,
+            profile,
+            profile_generation,
+            midi_output: None,
+            config,
             playing: Default::default(),
+            hits: [0; NUM_CHANNELS],
+            held: [0; NUM_CHANNELS],
+            pattern_engine: PatternEngine::new(),
+            midi_clock: MidiClock::default(),
+            clock_out: ClockOut::default(),
+            notes_on: [None; NUM_CHANNELS],
+            feedback_output: None,
+            ui_output: None,
+            ui_midi: None,
+            leds: Leds::default(),
+            flipping: [0; NUM_CHANNELS],
+            samples_generation: None,
             lowpass: Default::default(),
         }
     }
@@ -194,96 +417,433 @@ impl Synth {
     pub fn get_config(&self) -> Arc<Config> {
         self.config.clone()
     }
+
+    /// Where to send the midi messages generated during playback.
+    pub fn set_midi_output(&mut self, midi_output: channel::Sender<OutputEvent>) {
+        self.midi_output = Some(midi_output);
+    }
+
+    /// Where to send the led feedback for the controller.
+    pub fn set_feedback_output(&mut self, feedback_output: channel::Sender<OutputEvent>) {
+        self.feedback_output = Some(feedback_output);
+    }
+
+    /// Where to send the events that the ui handles using `Config::handle_ui_event`.
+    pub fn set_ui_output(&mut self, ui_output: channel::Sender<UiEvent>) {
+        self.ui_output = Some(ui_output);
+    }
+
+    /// Where the ui sends the messages for the midi output, they go out at the start of the next buffer.
+    pub fn set_ui_midi(&mut self, ui_midi: channel::Receiver<wmidi::MidiMessage<'static>>) {
+        self.ui_midi = Some(ui_midi);
+    }
+}
+
+fn send_midi(
+    midi_output: &Option<channel::Sender<OutputEvent>>,
+    frame: u64,
+    sample_rate: u32,
+    message: wmidi::MidiMessage<'static>,
+) {
+    if let Some(midi_output) = midi_output {
+        // a full queue means the output isn't keeping up, dropping is all we can do
+        let _ = midi_output.try_send(OutputEvent {
+            frame,
+            sample_rate,
+            message,
+        });
+    }
+}
+
+/// Frames into a buffer of `frames` corresponding to `us` microseconds into it.
+fn frame_offset(us: u64, sample_rate: u32, frames: usize) -> u64 {
+    (us * u64::from(sample_rate) / 1_000_000).min(frames.saturating_sub(1) as u64)
 }
 
 pub trait SynthPlayer {
+    /// Called off the audio thread before playing at `sample_rate`.
+    fn set_sample_rate(&mut self, sample_rate: u32);
     fn play(&mut self, sample_rate: u32, channels: usize, output: &mut [f32]);
 }
 
-impl SynthPlayer for Synth {
-    fn play(&mut self, sample_rate: u32, channels: usize, output: &mut [f32]) {
+impl Synth {
+    fn prepare(&mut self, sample_rate: u32, frames: usize) {
+        self.sample_rate = sample_rate;
         // set up samples
-        if self.sound_bank.is_none()
-            || self.sound_bank.as_ref().unwrap().get_sample_rate() != sample_rate
-        {
-            self.sound_bank = Some(sound_bank::Bank::new(sample_rate));
+        // the config resamples them, only swap in the result here and try again next buffer if it is busy
+        let generation = self.config.params.samples_generation.load();
+        if self.samples_generation != Some(generation) {
+            if let Some(bank) = self.config.params.bank.try_lock() {
+                self.sound_bank = bank.clone();
+                self.samples_generation = Some(generation);
+            }
+        }
+        // the ui may be editing the profile, keep using the previous one until it is done
+        let generation = self.config.params.profile_generation.load();
+        if self.profile_generation != generation {
+            if let Some(profile) = self.config.params.profile.try_lock() {
+                self.profile = profile.clone();
+                self.profile_generation = generation;
+            }
+        }
+
+        // queue midi messages
+        // the messages received during the previous buffer are applied with the same spacing during this one,
+        // delaying them by a buffer to avoid jitter
+        let now = timer::now_us();
+        let previous = self.last_prepare_us.replace(now);
+        let mut last_frame = self.pending_midi.back().map(|&(frame, _)| frame);
+        for event in self.midi_events.try_iter() {
+            if self.pending_midi.len() >= MAX_PENDING_MIDI {
+                // growing the queue would allocate
+                self.config.feedback.dropped_midi.fetch_add(1);
+                continue;
+            }
+            let offset = previous.map_or(0, |previous| {
+                frame_offset(event.time_us.saturating_sub(previous), sample_rate, frames)
+            });
+            // keep the order even if the inputs disagree a little on the time
+            let frame =
+                last_frame.map_or(self.clock + offset, |last| (self.clock + offset).max(last));
+            last_frame = Some(frame);
+            self.pending_midi.push_back((frame, event));
+        }
+
+        if !self.config.params.playing.load() {
+            self.flipping = [0; NUM_CHANNELS];
+        }
+        self.update_leds();
+
+        if let Some(ui_midi) = &self.ui_midi {
+            for message in ui_midi.try_iter() {
+                send_midi(&self.midi_output, self.clock, sample_rate, message);
+            }
         }
 
-        // pump midi messages
-        for message in self.midi_events.try_iter() {
-            match message {
-                wmidi::MidiMessage::NoteOn(_, note, _) => {
-                    let (quot, rem) = (note as usize).div_mod_floor(&(NOTES_PER_CHANNEL as usize));
-                    self.config.selected[quot].fetch_xor(1 << rem);
+        if self.config.params.clock_source.load() == ClockSource::External {
+            if let Some(bpm) = self.midi_clock.bpm(sample_rate) {
+                self.config.params.bpm.store(bpm.round().max(1.) as u32);
+            }
+        }
+    }
+
+    /// Send the led states that changed since the last update.
+    fn update_leds(&mut self) {
+        if self.feedback_output.is_none() {
+            return;
+        }
+        let profile = self.profile.clone();
+        let locked = array_init(|c| self.config.params.locked[c].load());
+        let selected = array_init(|c| self.config.selected[c].load());
+        let frame = self.clock;
+        let sample_rate = self.sample_rate;
+        let feedback_output = &self.feedback_output;
+        self.leds.update(
+            self.config
+                .params
+                .send_leds
+                .load()
+                .then_some(&profile.mapping),
+            &profile.leds,
+            &locked,
+            &selected,
+            &self.flipping,
+            |message| send_midi(feedback_output, frame, sample_rate, message),
+        );
+    }
+
+    /// Handle the midi messages that are due at the current frame.
+    fn apply_midi(&mut self) {
+        while let Some(&(frame, _)) = self.pending_midi.front() {
+            if frame > self.clock {
+                break;
+            }
+            let (_, event) = self.pending_midi.pop_front().unwrap();
+            self.handle_midi(event.message, event.mapped);
+        }
+    }
+
+    /// `mapped` is used instead of the active profile for note messages, see `InputEvent`.
+    fn handle_midi(&mut self, message: wmidi::MidiMessage<'static>, mapped: Option<(usize, u64)>) {
+        let profile = &self.profile;
+        match message {
+            wmidi::MidiMessage::NoteOn(channel, note, velocity) => {
+                if let Some((c, n)) =
+                    mapped.or_else(|| profile.mapping.map(channel.index(), note as u8))
+                {
+                    // some controllers send note on with zero velocity instead of note off
+                    let on = u8::from(velocity) != 0;
+                    self.config.note_input(&mut self.held, c, n, on);
                 }
-                wmidi::MidiMessage::NoteOff(_, note, _) => {
-                    let (quot, rem) = (note as usize).div_mod_floor(&(NOTES_PER_CHANNEL as usize));
-                    self.config.selected[quot].fetch_and(!(1 << rem));
+            }
+            wmidi::MidiMessage::NoteOff(channel, note, _) => {
+                if let Some((c, n)) =
+                    mapped.or_else(|| profile.mapping.map(channel.index(), note as u8))
+                {
+                    self.config.note_input(&mut self.held, c, n, false);
                 }
-                _ => {}
             }
+            wmidi::MidiMessage::ControlChange(channel, cc, value) => {
+                let deferred = self.config.control_change(
+                    profile,
+                    channel.index(),
+                    u8::from(cc),
+                    u8::from(value),
+                );
+                if let (Some(event), Some(ui_output)) = (deferred, &self.ui_output) {
+                    // a full queue means the ui isn't running, dropping is all we can do
+                    let _ = ui_output.try_send(event);
+                }
+            }
+            wmidi::MidiMessage::TimingClock => self.midi_clock.tick(self.clock),
+            wmidi::MidiMessage::Start
+            | wmidi::MidiMessage::Continue
+            | wmidi::MidiMessage::Stop
+            | wmidi::MidiMessage::SongPositionPointer(_) => {
+                match message {
+                    wmidi::MidiMessage::Start => self.midi_clock.start(),
+                    wmidi::MidiMessage::Continue => self.midi_clock.resume(),
+                    wmidi::MidiMessage::Stop => self.midi_clock.stop(),
+                    wmidi::MidiMessage::SongPositionPointer(position) => {
+                        self.midi_clock.song_position(u16::from(position))
+                    }
+                    _ => unreachable!(),
+                }
+                if self.config.params.clock_source.load() == ClockSource::External {
+                    self.config
+                        .params
+                        .playing
+                        .store(self.midi_clock.is_running());
+                }
+            }
+            wmidi::MidiMessage::OwnedSysEx(data) => {
+                // decoding, loading and building dumps all allocate, so the ui does it
+                if let Some(ui_output) = &self.ui_output {
+                    let _ = ui_output.try_send(UiEvent::SysEx(data));
+                }
+            }
+            _ => {}
         }
+    }
 
-        // produce sound
-        let frames_per_beat = sample_rate * 60 / (self.config.params.bpm.load() * 4);
-        let gain = 10f32.powf(self.config.params.gain_db.load() / 10f32);
-        let muted = self.config.params.muted.load();
-        let playing = self.config.params.playing.load();
-        for frame in output.chunks_exact_mut(channels) {
-            if playing {
-                let (beat, beat_frame) = self.clock.div_mod_floor(&frames_per_beat.into());
-                if beat_frame == 0 {
-                    for channel in 0..NUM_CHANNELS {
-                        let mut pattern = 0u32;
-                        const_assert!(PATTERN_LENGTH <= 32);
-                        for b in 0..PATTERN_LENGTH {
-                            if self.config.get_beat(channel, beat + b) {
-                                pattern |= 1 << (PATTERN_LENGTH - b - 1);
-                            }
-                        }
-                        self.config.feedback.channels[channel]
-                            .pattern
-                            .store(pattern);
-
-                        if (pattern >> (PATTERN_LENGTH - 1)) & 1 != 0 {
-                            self.playing[channel] = Some(TimedClip {
-                                start_clock: self.clock,
-                            });
-                        }
+    fn sends_clock(&self) -> bool {
+        self.midi_output.is_some()
+            && self.config.params.send_clock.load()
+            && self.config.params.clock_source.load() == ClockSource::Internal
+    }
+
+    /// Send midi clock for the current frame.
+    fn send_clock(&mut self, sample_rate: u32, frames_per_step: u32, playing: bool) {
+        let frame = self.clock;
+        let midi_output = &self.midi_output;
+        self.clock_out
+            .next_frame(frame, frames_per_step, playing, |message| {
+                send_midi(midi_output, frame, sample_rate, message)
+            });
+    }
+
+    /// Release the notes that were sent to the midi output.
+    fn release_notes(&mut self) {
+        let sample_rate = self.sample_rate;
+        for note in self.notes_on.iter_mut().filter_map(Option::take) {
+            send_midi(&self.midi_output, self.clock, sample_rate, note.note_off());
+        }
+    }
+
+    /// The step that starts on this frame, if any.
+    fn step_start(&mut self, frames_per_step: u32) -> Option<u64> {
+        match self.config.params.clock_source.load() {
+            ClockSource::Internal => {
+                let (step, step_frame) = self.clock.div_mod_floor(&frames_per_step.into());
+                (step_frame == 0).then_some(step)
+            }
+            ClockSource::External => self.midi_clock.next_frame(),
+        }
+    }
+
+    /// Advance one frame and return the output of each channel.
+    /// Channel volume and mute are ignored if `dry` is set.
+    fn next_frame(&mut self, frames_per_step: u32, muted: u64, dry: bool) -> [f32; NUM_CHANNELS] {
+        if let Some(beat) = self.step_start(frames_per_step) {
+            // notes last a step
+            self.release_notes();
+            let send_notes = self.midi_output.is_some() && self.config.params.send_notes.load();
+            let triggered: [u16; NUM_CHANNELS] =
+                array_init(|channel| self.config.get_triggered(channel));
+            let patterns = *self.pattern_engine.update(beat, &triggered);
+            let divisors = pattern::divisor_mask(beat);
+            self.flipping = array_init(|channel| triggered[channel] & divisors);
+            self.update_leds();
+            for (channel, &pattern) in patterns.iter().enumerate() {
+                self.config.feedback.channels[channel]
+                    .pattern
+                    .store(pattern);
+
+                if (pattern >> (PATTERN_LENGTH - 1)) & 1 != 0 {
+                    // seed the randomness from the position to keep bounces deterministic
+                    let seed = (self.clock as u32) ^ (channel as u32) << 24;
+                    let velocity = self.config.params.channel_velocities[channel].load();
+                    let layer = match (
+                        self.config.params.channel_samples[channel].load(),
+                        &self.sound_bank,
+                    ) {
+                        (Sound::Kit(slot), Some(bank)) => bank.choose_layer(
+                            slot,
+                            velocity,
+                            self.hits[channel],
+                            seed.wrapping_mul(0x9e37_79b9) >> 8,
+                        ),
+                        _ => 0,
+                    };
+                    self.hits[channel] += 1;
+                    if send_notes && (muted >> channel) & 1 == 0 {
+                        let note = self.config.params.output_notes[channel].load();
+                        send_midi(
+                            &self.midi_output,
+                            self.clock,
+                            self.sample_rate,
+                            note.note_on(velocity),
+                        );
+                        self.notes_on[channel] = Some(note);
                     }
+                    self.playing[channel] = Some(TimedClip {
+                        start_clock: self.clock,
+                        voice: voice::VoiceState::new(seed),
+                        layer,
+                        gain: f32::from(velocity) / 127f32,
+                    });
                 }
+            }
+        }
 
-                let mut value = 0f32;
-                for (i, sample, volume_db, lowpass) in multizip((
-                    0..,
-                    self.playing.iter_mut(),
-                    self.config.params.channel_volumes_db.iter(),
-                    self.lowpass.iter_mut(),
-                )) {
-                    let mut channel_value = 0f32;
-                    if (muted >> i) & 1 == 0 {
-                        if let Some(TimedClip { start_clock }) = *sample {
-                            let time_sample = self.clock - start_clock;
-                            if let Some(&v) = self
-                                .sound_bank
-                                .as_ref()
-                                .unwrap()
-                                .get_sound(self.config.params.channel_samples[i].load())
+        // samples are silent until they have been resampled for this rate
+        let sample_rate = self.sample_rate;
+        let bank = self
+            .sound_bank
+            .as_deref()
+            .filter(|bank| bank.get_sample_rate() == sample_rate);
+        let mut values = [0f32; NUM_CHANNELS];
+        for (i, value, sample, volume_db, lowpass) in multizip((
+            0..,
+            values.iter_mut(),
+            self.playing.iter_mut(),
+            self.config.params.channel_volumes_db.iter(),
+            self.lowpass.iter_mut(),
+        )) {
+            let mut channel_value = 0f32;
+            if dry || (muted >> i) & 1 == 0 {
+                if let Some(TimedClip {
+                    start_clock,
+                    voice,
+                    layer,
+                    gain,
+                }) = sample
+                {
+                    let time_sample = self.clock - *start_clock;
+                    let v = match self.config.params.channel_samples[i].load() {
+                        Sound::Kit(slot) => bank.and_then(|bank| {
+                            bank.get_sound(slot, *layer)
                                 .get(time_sample as usize)
-                            {
-                                channel_value = v * 10f32.powf(volume_db.load() / 10f32);
-                            } else {
-                                *sample = None;
-                            }
-                        }
+                                .copied()
+                        }),
+                        Sound::Voice(v) => voice.next(
+                            v,
+                            &self.config.params.channel_voice_params[i].load(),
+                            sample_rate,
+                            time_sample,
+                        ),
+                        Sound::User => bank.and_then(|bank| {
+                            bank.get_user_sound(i).get(time_sample as usize).copied()
+                        }),
+                    };
+                    if let Some(v) = v {
+                        let v = v * *gain;
+                        channel_value = if dry {
+                            v
+                        } else {
+                            v * 10f32.powf(volume_db.load() / 10f32)
+                        };
+                    } else {
+                        *sample = None;
                     }
-                    // TODO do proper lowpass
-                    const LOWPASS_AMOUNT: f32 = 0.1;
-                    *lowpass = LOWPASS_AMOUNT * *lowpass + (1f32 - LOWPASS_AMOUNT) * channel_value;
-                    value += *lowpass;
                 }
+            }
+            // TODO do proper lowpass
+            const LOWPASS_AMOUNT: f32 = 0.1;
+            *lowpass = LOWPASS_AMOUNT * *lowpass + (1f32 - LOWPASS_AMOUNT) * channel_value;
+            *value = *lowpass;
+        }
+        self.clock += 1;
+        values
+    }
 
-                value *= gain;
+    /// Like `SynthPlayer::play`, but writes each channel to its own mono output instead of mixing them.
+    /// The master gain is only applied if `dry` is not set.
+    pub fn play_channels(
+        &mut self,
+        sample_rate: u32,
+        dry: bool,
+        output: &mut [[f32; NUM_CHANNELS]],
+    ) {
+        self.prepare(sample_rate, output.len());
+        let frames_per_step = frames_per_step(sample_rate, self.config.params.bpm.load());
+        let gain = if dry {
+            1f32
+        } else {
+            10f32.powf(self.config.params.gain_db.load() / 10f32)
+        };
+        let muted = self.config.params.muted.load();
+        let playing = self.config.params.playing.load();
+        let send_clock = self.sends_clock();
+        if !playing {
+            self.release_notes();
+        }
+        for frame in output.iter_mut() {
+            self.apply_midi();
+            if send_clock {
+                self.send_clock(sample_rate, frames_per_step, playing);
+            }
+            if playing {
+                *frame = self.next_frame(frames_per_step, muted, dry);
+                for value in frame.iter_mut() {
+                    *value *= gain;
+                }
+            } else {
+                *frame = [0f32; NUM_CHANNELS];
+                self.clock += 1;
+            }
+        }
+    }
+}
+
+impl SynthPlayer for Synth {
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.config.set_sample_rate(sample_rate);
+    }
+
+    fn play(&mut self, sample_rate: u32, channels: usize, output: &mut [f32]) {
+        self.prepare(sample_rate, output.len() / channels);
+
+        // produce sound
+        let frames_per_beat = frames_per_step(sample_rate, self.config.params.bpm.load());
+        let gain = 10f32.powf(self.config.params.gain_db.load() / 10f32);
+        let muted = self.config.params.muted.load();
+        let playing = self.config.params.playing.load();
+        let send_clock = self.sends_clock();
+        if !playing {
+            self.release_notes();
+        }
+        for frame in output.chunks_exact_mut(channels) {
+            self.apply_midi();
+            if send_clock {
+                self.send_clock(sample_rate, frames_per_beat, playing);
+            }
+            if playing {
+                let value = self
+                    .next_frame(frames_per_beat, muted, false)
+                    .iter()
+                    .sum::<f32>()
+                    * gain;
 
                 for sample in frame.iter_mut() {
                     *sample = value;
@@ -292,16 +852,19 @@ impl SynthPlayer for Synth {
                 for sample in frame.iter_mut() {
                     *sample = 0f32;
                 }
+                self.clock += 1;
             }
-            self.clock += 1;
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Synth, SynthPlayer};
+    use super::{
+        kit::Kit, sysex, InputEvent, OutputNote, Synth, SynthPlayer, UiEvent, MAX_PENDING_MIDI,
+    };
     use crossbeam::channel;
+    use std::{sync::Arc, thread, time::Duration};
 
     #[test]
     fn silence() {
@@ -311,4 +874,171 @@ mod test {
         synth.play(48000, 2, &mut data);
         assert_eq!([0f32; 512], data);
     }
+
+    #[test]
+    fn switch_kit() {
+        let (_tx, rx) = channel::bounded(1);
+        let mut synth = Synth::new(rx);
+        let config = synth.get_config();
+        synth.set_sample_rate(48000);
+        let mut data = [0f32; 512];
+        synth.play(48000, 2, &mut data);
+        config.set_kit(Arc::new(Kit::builtin()));
+        synth.play(48000, 2, &mut data);
+        // the synth gets the kit already resampled
+        let bank = config.params.bank.lock().clone().unwrap();
+        assert!(Arc::ptr_eq(&bank, synth.sound_bank.as_ref().unwrap()));
+        assert!(!bank.get_sound(0, 0).is_empty());
+    }
+
+    #[test]
+    fn note_output() {
+        let (_tx, rx) = channel::bounded(1);
+        let (out_tx, out_rx) = channel::bounded(16);
+        let mut synth = Synth::new(rx);
+        synth.set_midi_output(out_tx);
+        let config = synth.get_config();
+        config.params.send_notes.store(true);
+        // every step on the first channel, 6000 frames per step
+        config.params.locked[0].store(1);
+        let mut data = vec![0f32; 6001 * 2];
+        synth.play(48000, 2, &mut data);
+        let kick = OutputNote::gm_drum(0);
+        assert_eq!(
+            vec![
+                (0, kick.note_on(127)),
+                (6000, kick.note_off()),
+                (6000, kick.note_on(127))
+            ],
+            out_rx
+                .try_iter()
+                .map(|e| (e.frame, e.message))
+                .collect::<Vec<_>>()
+        );
+        config.params.playing.store(false);
+        synth.play(48000, 2, &mut data);
+        assert_eq!(
+            vec![(6001, kick.note_off())],
+            out_rx
+                .try_iter()
+                .map(|e| (e.frame, e.message))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn profile_locked() {
+        let (tx, rx) = channel::bounded(1);
+        let (feedback_tx, feedback_rx) = channel::bounded(1024);
+        let mut synth = Synth::new(rx);
+        synth.set_feedback_output(feedback_tx);
+        let config = synth.get_config();
+        config.params.send_leds.store(true);
+        tx.send(InputEvent {
+            time_us: 0,
+            message: wmidi::MidiMessage::NoteOn(
+                wmidi::Channel::Ch1,
+                wmidi::Note::CMinus1,
+                wmidi::Velocity::MAX,
+            ),
+            mapped: None,
+        })
+        .unwrap();
+        // the ui holds the lock while editing the profile, the audio thread must not wait for it
+        let profile = config.params.profile.lock();
+        let (done_tx, done_rx) = channel::bounded(1);
+        thread::spawn(move || {
+            let mut data = [0f32; 512];
+            synth.play(48000, 2, &mut data);
+            done_tx.send(()).unwrap();
+        });
+        done_rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the synth waited for the profile");
+        drop(profile);
+        assert_eq!(1, config.selected[0].load());
+        assert!(feedback_rx.try_iter().count() > 0);
+    }
+
+    #[test]
+    fn sysex_request() {
+        let (tx, rx) = channel::bounded(1);
+        let (out_tx, out_rx) = channel::bounded(16);
+        let (ui_tx, ui_rx) = channel::bounded(16);
+        let (ui_midi_tx, ui_midi_rx) = channel::bounded(16);
+        let mut synth = Synth::new(rx);
+        synth.set_midi_output(out_tx);
+        synth.set_ui_output(ui_tx);
+        synth.set_ui_midi(ui_midi_rx);
+        let config = synth.get_config();
+        config.params.playing.store(false);
+        tx.send(InputEvent {
+            time_us: 0,
+            message: sysex::Message::Request.to_midi(),
+            mapped: None,
+        })
+        .unwrap();
+        let mut data = [0f32; 512];
+        synth.play(48000, 2, &mut data);
+        // the audio thread leaves the request to the ui
+        assert_eq!(0, out_rx.len());
+        let event = ui_rx.try_recv().unwrap();
+        assert!(matches!(event, UiEvent::SysEx(_)));
+        let dump = config.handle_ui_event(event).unwrap();
+        ui_midi_tx.send(dump.clone()).unwrap();
+        synth.play(48000, 2, &mut data);
+        assert_eq!(
+            vec![(256, dump)],
+            out_rx
+                .try_iter()
+                .map(|e| (e.frame, e.message))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn midi_overflow() {
+        let (tx, rx) = channel::bounded(1024);
+        let mut synth = Synth::new(rx);
+        let config = synth.get_config();
+        for _ in 0..MAX_PENDING_MIDI + 10 {
+            tx.send(InputEvent {
+                time_us: 0,
+                message: wmidi::MidiMessage::TimingClock,
+                mapped: None,
+            })
+            .unwrap();
+        }
+        let capacity = synth.pending_midi.capacity();
+        synth.prepare(48000, 512);
+        assert_eq!(MAX_PENDING_MIDI, synth.pending_midi.len());
+        assert_eq!(capacity, synth.pending_midi.capacity());
+        assert_eq!(10, config.feedback.dropped_midi.load());
+    }
+
+    #[test]
+    fn midi_timing() {
+        let (tx, rx) = channel::bounded(1);
+        let mut synth = Synth::new(rx);
+        let config = synth.get_config();
+        synth.last_prepare_us = Some(1_000_000);
+        // 1ms into the previous buffer is 48 frames
+        tx.send(InputEvent {
+            time_us: 1_001_000,
+            message: wmidi::MidiMessage::NoteOn(
+                wmidi::Channel::Ch1,
+                wmidi::Note::CMinus1,
+                wmidi::Velocity::MAX,
+            ),
+            mapped: None,
+        })
+        .unwrap();
+        synth.prepare(48000, 512);
+        synth.clock = 47;
+        synth.apply_midi();
+        assert_eq!(0, config.selected[0].load());
+        synth.clock = 48;
+        synth.apply_midi();
+        assert_eq!(1, config.selected[0].load());
+    }
 }
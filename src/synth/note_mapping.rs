@@ -0,0 +1,348 @@
+use super::{
+    led_feedback::LedColors, midi_learn::CcBinding, Config, NOTES_PER_CHANNEL, NUM_CHANNELS,
+};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use strum_macros::Display;
+
+/// What pressing and releasing the notes of a channel does.
+#[derive(
+    Copy, Clone, Debug, Default, Display, enum_iterator::Sequence, PartialEq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum NoteMode {
+    /// notes play while held
+    #[default]
+    #[strum(serialize = "momentary")]
+    Momentary,
+    /// pressing a note flips its locked state
+    #[strum(serialize = "toggle")]
+    Toggle,
+    /// a chord stays selected until the next one is pressed
+    #[strum(serialize = "latch")]
+    Latch,
+}
+
+/// A single key of a custom key map.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KeyBinding {
+    /// midi channel 1 to 16, or any channel if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub midi_channel: Option<u8>,
+    pub note: u8,
+    /// 1 to NUM_CHANNELS
+    pub channel: usize,
+    /// 1 to NOTES_PER_CHANNEL
+    pub divisor: u64,
+}
+
+/// How incoming midi notes select drum channels and divisors.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum NoteMapping {
+    /// Runs of NOTES_PER_CHANNEL notes from `base_note`, one run per drum channel. Ignores the midi channel.
+    Chromatic { base_note: u8 },
+    /// Midi channel n plays drum channel n, with the divisors on the notes from `base_note`.
+    ChannelPerDrum { base_note: u8 },
+    /// Explicit keys, for pad controllers.
+    KeyMap { keys: Vec<KeyBinding> },
+}
+
+impl NoteMapping {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            NoteMapping::Chromatic { base_note } => {
+                if *base_note > 127 {
+                    bail!("base note must be at most 127");
+                }
+            }
+            NoteMapping::ChannelPerDrum { base_note } => {
+                if u64::from(*base_note) + NOTES_PER_CHANNEL > 128 {
+                    bail!("base note must be at most {}", 128 - NOTES_PER_CHANNEL);
+                }
+            }
+            NoteMapping::KeyMap { keys } => {
+                for key in keys {
+                    if let Some(c) = key.midi_channel {
+                        if !(1..=16).contains(&c) {
+                            bail!("invalid midi channel {}, must be between 1 and 16", c);
+                        }
+                    }
+                    if key.note > 127 {
+                        bail!("invalid note {}, must be at most 127", key.note);
+                    }
+                    if !(1..=NUM_CHANNELS).contains(&key.channel) {
+                        bail!(
+                            "invalid channel {}, must be between 1 and {}",
+                            key.channel,
+                            NUM_CHANNELS
+                        );
+                    }
+                    if !(1..=NOTES_PER_CHANNEL).contains(&key.divisor) {
+                        bail!(
+                            "invalid divisor {}, must be between 1 and {}",
+                            key.divisor,
+                            NOTES_PER_CHANNEL
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drum channel and note index (divisor - 1) played by `note` on `midi_channel` (0 to 15).
+    /// None if the note isn't mapped.
+    pub fn map(&self, midi_channel: u8, note: u8) -> Option<(usize, u64)> {
+        match self {
+            NoteMapping::Chromatic { base_note } => {
+                let offset = u64::from(note.checked_sub(*base_note)?);
+                let channel = (offset / NOTES_PER_CHANNEL) as usize;
+                (channel < NUM_CHANNELS).then_some((channel, offset % NOTES_PER_CHANNEL))
+            }
+            NoteMapping::ChannelPerDrum { base_note } => {
+                let offset = u64::from(note.checked_sub(*base_note)?);
+                let channel = usize::from(midi_channel);
+                (channel < NUM_CHANNELS && offset < NOTES_PER_CHANNEL).then_some((channel, offset))
+            }
+            NoteMapping::KeyMap { keys } => keys
+                .iter()
+                .find(|key| {
+                    key.note == note && key.midi_channel.is_none_or(|c| c == midi_channel + 1)
+                })
+                .map(|key| (key.channel - 1, key.divisor - 1)),
+        }
+    }
+
+    /// Midi channel (0 to 15) and note that play `note` (divisor - 1) of drum `channel`, the inverse of `map`.
+    pub fn key(&self, channel: usize, note: u64) -> Option<(u8, u8)> {
+        match self {
+            NoteMapping::Chromatic { base_note } => {
+                let key = u64::from(*base_note) + channel as u64 * NOTES_PER_CHANNEL + note;
+                (key < 128).then_some((0, key as u8))
+            }
+            NoteMapping::ChannelPerDrum { base_note } => {
+                let key = u64::from(*base_note) + note;
+                (channel < 16 && key < 128).then_some((channel as u8, key as u8))
+            }
+            NoteMapping::KeyMap { keys } => keys
+                .iter()
+                .find(|key| key.channel == channel + 1 && key.divisor == note + 1)
+                .map(|key| (key.midi_channel.map_or(0, |c| c - 1), key.note)),
+        }
+    }
+}
+
+/// A named note mapping and cc bindings, stored as json so it can be reused with the same controller.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub mapping: NoteMapping,
+    /// parameters controlled by midi cc, see `midi_learn`
+    #[serde(default)]
+    pub cc: Vec<CcBinding>,
+    /// colours of the led feedback
+    #[serde(default)]
+    pub leds: LedColors,
+}
+
+impl Profile {
+    pub fn from_json(data: &str) -> Result<Self> {
+        let profile: Self = serde_json::from_str(data)?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.mapping.validate()?;
+        self.leds.validate()?;
+        for binding in &self.cc {
+            binding.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn builtin() -> Vec<Self> {
+        vec![
+            Self {
+                name: "Chromatic".into(),
+                mapping: NoteMapping::Chromatic { base_note: 0 },
+                cc: vec![],
+                leds: LedColors::default(),
+            },
+            Self {
+                name: "Channel per drum".into(),
+                // c3
+                mapping: NoteMapping::ChannelPerDrum { base_note: 48 },
+                cc: vec![],
+                leds: LedColors::default(),
+            },
+        ]
+    }
+}
+
+impl Config {
+    /// Handle a note being pressed or released according to the mode of `channel`.
+    /// `held` keeps track of the currently pressed notes of each channel.
+    pub(super) fn note_input(
+        &self,
+        held: &mut [u16; NUM_CHANNELS],
+        channel: usize,
+        note: u64,
+        on: bool,
+    ) {
+        let bit = 1 << note;
+        let mode = self.params.channel_note_modes[channel].load();
+        if on {
+            match mode {
+                NoteMode::Momentary => {
+                    self.selected[channel].fetch_or(bit);
+                }
+                NoteMode::Toggle => {
+                    self.params.locked[channel].fetch_xor(bit);
+                }
+                NoteMode::Latch => {
+                    if held[channel] == 0 {
+                        self.selected[channel].store(bit);
+                    } else {
+                        self.selected[channel].fetch_or(bit);
+                    }
+                }
+            }
+            held[channel] |= bit;
+        } else {
+            if mode == NoteMode::Momentary {
+                self.selected[channel].fetch_and(!bit);
+            }
+            held[channel] &= !bit;
+        }
+    }
+
+    /// Nothing is changed if the profile is invalid.
+    pub fn set_profile(&self, profile: Arc<Profile>) -> Result<()> {
+        profile.validate()?;
+        *self.params.profile.lock() = profile;
+        self.params.profile_generation.fetch_add(1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeyBinding, NoteMapping, NoteMode, Profile};
+    use crate::synth::{Config, NUM_CHANNELS};
+
+    #[test]
+    fn chromatic() {
+        let mapping = NoteMapping::Chromatic { base_note: 0 };
+        assert_eq!(Some((0, 0)), mapping.map(3, 0));
+        assert_eq!(Some((5, 1)), mapping.map(0, 61));
+        assert_eq!(Some((10, 7)), mapping.map(0, 127));
+        let mapping = NoteMapping::Chromatic { base_note: 36 };
+        assert_eq!(None, mapping.map(0, 35));
+        assert_eq!(Some((0, 0)), mapping.map(0, 36));
+        assert_eq!(Some((7, 7)), mapping.map(0, 127));
+        assert_eq!(Some((0, 127)), mapping.key(7, 7));
+        assert_eq!(None, mapping.key(8, 0));
+    }
+
+    #[test]
+    fn channel_per_drum() {
+        let mapping = NoteMapping::ChannelPerDrum { base_note: 48 };
+        assert_eq!(Some((2, 4)), mapping.map(2, 52));
+        assert_eq!(None, mapping.map(2, 60));
+        assert_eq!(None, mapping.map(15, 48));
+        assert!(NoteMapping::ChannelPerDrum { base_note: 120 }
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn key_map() {
+        let mapping = NoteMapping::KeyMap {
+            keys: vec![
+                KeyBinding {
+                    midi_channel: Some(10),
+                    note: 36,
+                    channel: 1,
+                    divisor: 4,
+                },
+                KeyBinding {
+                    midi_channel: None,
+                    note: 38,
+                    channel: 2,
+                    divisor: 1,
+                },
+            ],
+        };
+        assert!(mapping.validate().is_ok());
+        assert_eq!(Some((0, 3)), mapping.map(9, 36));
+        assert_eq!(None, mapping.map(0, 36));
+        assert_eq!(Some((1, 0)), mapping.map(4, 38));
+        assert_eq!(Some((9, 36)), mapping.key(0, 3));
+        assert_eq!(None, mapping.key(0, 0));
+        let invalid = NoteMapping::KeyMap {
+            keys: vec![KeyBinding {
+                midi_channel: None,
+                note: 36,
+                channel: 12,
+                divisor: 1,
+            }],
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn profile_json() {
+        for profile in Profile::builtin() {
+            assert_eq!(
+                profile,
+                Profile::from_json(&profile.to_json().unwrap()).unwrap()
+            );
+        }
+        assert!(Profile::from_json(
+            r#"{"name": "pads", "mapping": {"mode": "key_map", "keys": [{"note": 36, "channel": 1, "divisor": 13}]}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn note_modes() {
+        let config = Config::new();
+        let mut held = [0u16; NUM_CHANNELS];
+        config.note_input(&mut held, 0, 0, true);
+        config.note_input(&mut held, 0, 2, true);
+        assert_eq!(0b101, config.selected[0].load());
+        config.note_input(&mut held, 0, 0, false);
+        assert_eq!(0b100, config.selected[0].load());
+        config.note_input(&mut held, 0, 2, false);
+        assert_eq!(0, config.selected[0].load());
+
+        config.params.channel_note_modes[1].store(NoteMode::Toggle);
+        config.note_input(&mut held, 1, 3, true);
+        config.note_input(&mut held, 1, 3, false);
+        assert_eq!(0b1000, config.params.locked[1].load());
+        config.note_input(&mut held, 1, 3, true);
+        config.note_input(&mut held, 1, 3, false);
+        assert_eq!(0, config.params.locked[1].load());
+        assert_eq!(0, config.selected[1].load());
+
+        config.params.channel_note_modes[2].store(NoteMode::Latch);
+        config.note_input(&mut held, 2, 0, true);
+        config.note_input(&mut held, 2, 1, true);
+        config.note_input(&mut held, 2, 0, false);
+        config.note_input(&mut held, 2, 1, false);
+        assert_eq!(0b11, config.selected[2].load());
+        // the next chord replaces the previous one
+        config.note_input(&mut held, 2, 4, true);
+        assert_eq!(0b10000, config.selected[2].load());
+        config.note_input(&mut held, 2, 5, true);
+        config.note_input(&mut held, 2, 4, false);
+        assert_eq!(0b110000, config.selected[2].load());
+    }
+}
@@ -0,0 +1,161 @@
+use super::{NOTES_PER_CHANNEL, NUM_CHANNELS, PATTERN_LENGTH};
+use static_assertions::const_assert;
+
+const_assert!(PATTERN_LENGTH <= 32);
+const PATTERN_MASK: u32 = ((1u64 << PATTERN_LENGTH) - 1) as u32;
+
+/// Mask where bit n is set if the square wave of note n flips at `step`.
+/// That is when `step` is divisible by the divisor of note n, except at step 0 which is compared against the wrapped around previous step.
+pub fn divisor_mask(step: u64) -> u16 {
+    let mut mask = 0;
+    for n in 0..NOTES_PER_CHANNEL {
+        // TODO use different divisors. n2, fib?
+        let div = n + 1;
+        let flips = if step == 0 {
+            (u64::MAX / div) & 1 != 0
+        } else {
+            step.is_multiple_of(div)
+        };
+        if flips {
+            mask |= 1 << n;
+        }
+    }
+    mask
+}
+
+/// Each triggered note is a square wave that flips every `div` steps.
+/// The xor of those waves changes, and thus produces a hit, when an odd number of them flip on the same step.
+pub fn is_hit(triggered: u16, divisors: u16) -> bool {
+    (triggered & divisors).count_ones() & 1 != 0
+}
+
+/// Keeps track of the upcoming pattern of each channel.
+/// The patterns are shifted one step at a time and only fully recomputed when a channel's notes change.
+#[derive(Clone)]
+pub struct PatternEngine {
+    /// divisor masks of the steps in the current window, indexed by step % PATTERN_LENGTH
+    divisors: [u16; PATTERN_LENGTH as usize],
+    triggered: [u16; NUM_CHANNELS],
+    patterns: [u32; NUM_CHANNELS],
+    step: Option<u64>,
+}
+
+impl Default for PatternEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PatternEngine {
+    pub fn new() -> Self {
+        Self {
+            divisors: [0; PATTERN_LENGTH as usize],
+            triggered: [0; NUM_CHANNELS],
+            patterns: [0; NUM_CHANNELS],
+            step: None,
+        }
+    }
+
+    fn slot(step: u64) -> usize {
+        (step % PATTERN_LENGTH) as usize
+    }
+
+    fn compute_pattern(&self, step: u64, triggered: u16) -> u32 {
+        let mut pattern = 0;
+        for b in 0..PATTERN_LENGTH {
+            if is_hit(triggered, self.divisors[Self::slot(step + b)]) {
+                pattern |= 1 << (PATTERN_LENGTH - b - 1);
+            }
+        }
+        pattern
+    }
+
+    /// Move the window to start at `step` using the notes in `triggered` (selected | locked per channel).
+    /// The msb of each returned pattern is `step` and the following bits are the steps after it.
+    pub fn update(&mut self, step: u64, triggered: &[u16; NUM_CHANNELS]) -> &[u32; NUM_CHANNELS] {
+        match self.step {
+            Some(current) if current == step => {}
+            Some(current) if current.wrapping_add(1) == step => {
+                let last = step + PATTERN_LENGTH - 1;
+                let divisors = divisor_mask(last);
+                self.divisors[Self::slot(last)] = divisors;
+                for (pattern, &t) in self.patterns.iter_mut().zip(self.triggered.iter()) {
+                    *pattern = ((*pattern << 1) & PATTERN_MASK) | u32::from(is_hit(t, divisors));
+                }
+            }
+            _ => {
+                for b in step..step + PATTERN_LENGTH {
+                    self.divisors[Self::slot(b)] = divisor_mask(b);
+                }
+                for channel in 0..NUM_CHANNELS {
+                    self.patterns[channel] = self.compute_pattern(step, self.triggered[channel]);
+                }
+            }
+        }
+        self.step = Some(step);
+        for (channel, &t) in triggered.iter().enumerate() {
+            if self.triggered[channel] != t {
+                self.triggered[channel] = t;
+                self.patterns[channel] = self.compute_pattern(step, t);
+            }
+        }
+        &self.patterns
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_hit, PatternEngine};
+    use crate::synth::{NUM_CHANNELS, PATTERN_LENGTH};
+
+    // the original formulation, xoring square waves of each divisor
+    fn square_wave_hit(triggered: u16, step: u64) -> bool {
+        let f = |b: u64| {
+            let mut a = false;
+            for n in 0..12 {
+                if triggered & (1 << n) != 0 {
+                    a ^= (b / (n + 1)) & 1 == 0;
+                }
+            }
+            a
+        };
+        f(step) != f(step.wrapping_sub(1))
+    }
+
+    #[test]
+    fn matches_square_waves() {
+        for triggered in [0u16, 1, 0b101, 0b1000_0001_0010, 0xfff] {
+            for step in 0..1000 {
+                assert_eq!(
+                    square_wave_hit(triggered, step),
+                    is_hit(triggered, super::divisor_mask(step))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_matches_recompute() {
+        let mut engine = PatternEngine::new();
+        let mut triggered = [0u16; NUM_CHANNELS];
+        let mut step = 0;
+        for i in 0..500u64 {
+            // change notes now and then, and jump around occasionally
+            if i % 7 == 0 {
+                triggered[(i % NUM_CHANNELS as u64) as usize] = (i * 37 % 4096) as u16;
+            }
+            step = if i % 97 == 0 { step + 13 } else { step + 1 };
+            let patterns = *engine.update(step, &triggered);
+            let fresh = *PatternEngine::new().update(step, &triggered);
+            assert_eq!(fresh, patterns);
+            for (&pattern, &t) in patterns.iter().zip(triggered.iter()) {
+                for b in 0..PATTERN_LENGTH {
+                    assert_eq!(
+                        (pattern >> (PATTERN_LENGTH - b - 1)) & 1 != 0,
+                        square_wave_hit(t, step + b)
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use super::{
+    kit::Kit,
+    midi_out::OutputNote,
+    note_mapping::NoteMode,
+    sound_bank::{Sound, UserSample},
+    voice::VoiceParams,
+    Config, NOTES_PER_CHANNEL, NUM_CHANNELS,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, sync::Arc};
+
+/// Saved state of a single channel.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelProject {
+    /// locked divisors, 1 to NOTES_PER_CHANNEL
+    pub divisors: Vec<u64>,
+    /// name of a sample of the kit or a synthesized voice, the kit's default for the channel if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample: Option<String>,
+    /// parameters of a synthesized voice, its defaults are used if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voice_params: Option<VoiceParams>,
+    /// wav file of a user sample, relative to the project file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    pub volume_db: f32,
+    /// 1 to 127
+    pub velocity: u8,
+    pub note_mode: NoteMode,
+    pub muted: bool,
+    /// note sent to the midi output on hits, the general midi drum note of the sound if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub midi_output: Option<OutputNote>,
+}
+
+impl Default for ChannelProject {
+    fn default() -> Self {
+        Self {
+            divisors: vec![],
+            sample: None,
+            voice_params: None,
+            file: None,
+            volume_db: 0f32,
+            velocity: 127,
+            note_mode: NoteMode::Momentary,
+            muted: false,
+            midi_output: None,
+        }
+    }
+}
+
+/// Everything needed to recreate a pattern, stored as json.
+/// Missing fields and channels get their default values.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Project {
+    /// manifest of the kit, the built-in kit is used if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kit: Option<String>,
+    pub bpm: u32,
+    pub gain_db: f32,
+    pub channels: Vec<ChannelProject>,
+}
+
+impl Default for Project {
+    fn default() -> Self {
+        Self {
+            kit: None,
+            bpm: 120,
+            gain_db: 0f32,
+            channels: vec![],
+        }
+    }
+}
+
+impl Project {
+    pub fn from_json(data: &str) -> Result<Self> {
+        Ok(serde_json::from_str(data)?)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Load the kit, the state and the user samples of the project into `config`.
+    /// Files are relative to `base_dir`.
+    pub fn load_into(&self, config: &Config, base_dir: &Path) -> Result<()> {
+        let builtin_kit = config.params.kit.lock().path.is_none();
+        match &self.kit {
+            Some(file) => config.set_kit(Arc::new(Kit::from_file(&base_dir.join(file))?)),
+            None if !builtin_kit => config.set_kit(Arc::new(Kit::builtin())),
+            None => {}
+        }
+        config.load_project(self)?;
+        self.load_user_samples(config, base_dir)
+    }
+
+    /// Read the wav files of the channels that play a user sample into `config`.
+    pub fn load_user_samples(&self, config: &Config, base_dir: &Path) -> Result<()> {
+        for (c, channel) in self.channels.iter().enumerate() {
+            if let (Some("User"), Some(file)) = (channel.sample.as_deref(), &channel.file) {
+                let path = base_dir.join(file);
+                let data = std::fs::read(&path)
+                    .with_context(|| format!("unable to read {}", path.display()))?;
+                let name = path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| file.clone());
+                let sample = UserSample::from_wav(name, Some(file.clone()), &data)
+                    .with_context(|| format!("unable to load {}", path.display()))?;
+                config.set_user_sample(c, Some(Arc::new(sample)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn to_project(&self) -> Project {
+        let muted = self.params.muted.load();
+        let kit = self.params.kit.lock().path.clone();
+        Project {
+            kit,
+            bpm: self.params.bpm.load(),
+            gain_db: self.params.gain_db.load(),
+            channels: (0..NUM_CHANNELS)
+                .map(|c| {
+                    let locked = self.params.locked[c].load();
+                    let sample = self.params.channel_samples[c].load();
+                    ChannelProject {
+                        divisors: (0..NOTES_PER_CHANNEL)
+                            .filter(|n| locked & (1 << n) != 0)
+                            .map(|n| n + 1)
+                            .collect(),
+                        sample: Some(self.sound_name(sample)),
+                        voice_params: match sample {
+                            Sound::Voice(_) => Some(self.params.channel_voice_params[c].load()),
+                            Sound::Kit(_) | Sound::User => None,
+                        },
+                        file: match sample {
+                            Sound::User => self.params.user_samples[c]
+                                .lock()
+                                .as_ref()
+                                .map(|s| s.path.clone().unwrap_or_else(|| s.name.clone())),
+                            _ => None,
+                        },
+                        volume_db: self.params.channel_volumes_db[c].load(),
+                        velocity: self.params.channel_velocities[c].load(),
+                        note_mode: self.params.channel_note_modes[c].load(),
+                        muted: (muted >> c) & 1 != 0,
+                        midi_output: self.params.channel_output_notes[c].load(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Replace the current state with `project`. Nothing is changed if the project is invalid.
+    pub fn load_project(&self, project: &Project) -> Result<()> {
+        if project.bpm == 0 {
+            bail!("bpm must be positive");
+        }
+        if project.channels.len() > NUM_CHANNELS {
+            bail!(
+                "too many channels: {}, max is {}",
+                project.channels.len(),
+                NUM_CHANNELS
+            );
+        }
+        let mut locked = [0u16; NUM_CHANNELS];
+        let mut sounds = [None; NUM_CHANNELS];
+        for (channel, l, sound) in itertools::multizip((
+            project.channels.iter(),
+            locked.iter_mut(),
+            sounds.iter_mut(),
+        )) {
+            if !(1..=127).contains(&channel.velocity) {
+                bail!(
+                    "invalid velocity {}, must be between 1 and 127",
+                    channel.velocity
+                );
+            }
+            if let Some(note) = &channel.midi_output {
+                note.validate()?;
+            }
+            if let Some(name) = &channel.sample {
+                *sound = Some(
+                    self.find_sound(name)
+                        .ok_or_else(|| anyhow!("unknown sound {}", name))?,
+                );
+            }
+            if let (Some(Sound::Voice(voice)), Some(params)) = (*sound, &channel.voice_params) {
+                voice.validate_params(params)?;
+            }
+            for &divisor in &channel.divisors {
+                if !(1..=NOTES_PER_CHANNEL).contains(&divisor) {
+                    bail!(
+                        "invalid divisor {}, must be between 1 and {}",
+                        divisor,
+                        NOTES_PER_CHANNEL
+                    );
+                }
+                *l |= 1 << (divisor - 1);
+            }
+        }
+
+        self.params.bpm.store(project.bpm);
+        self.params.gain_db.store(project.gain_db);
+        let default_channel = ChannelProject::default();
+        let kit_channels = self.params.kit.lock().channels.clone();
+        let mut muted = 0;
+        for (c, (&l, sound)) in locked.iter().zip(sounds).enumerate() {
+            let channel = project.channels.get(c).unwrap_or(&default_channel);
+            let sound = sound
+                .unwrap_or_else(|| Sound::Kit(kit_channels.get(c).map(|k| k.sample).unwrap_or(0)));
+            self.params.locked[c].store(l);
+            self.params.channel_samples[c].store(sound);
+            if let Sound::Voice(voice) = sound {
+                self.params.channel_voice_params[c].store(
+                    channel
+                        .voice_params
+                        .unwrap_or_else(|| voice.default_params()),
+                );
+            }
+            self.params.channel_volumes_db[c].store(channel.volume_db);
+            self.params.channel_velocities[c].store(channel.velocity);
+            self.params.channel_note_modes[c].store(channel.note_mode);
+            self.params.channel_output_notes[c].store(channel.midi_output);
+            if channel.muted {
+                muted |= 1 << c;
+            }
+        }
+        self.params.muted.store(muted);
+        self.update_output_notes();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NoteMode, OutputNote, Project};
+    use crate::synth::{sound_bank::Sound, voice::Voice, Config};
+
+    #[test]
+    fn roundtrip() {
+        let project = Project::from_json(
+            r#"{
+                "bpm": 96,
+                "channels": [
+                    {"divisors": [1, 3], "sample": "Kick", "volume_db": -2.0, "note_mode": "latch"},
+                    {"sample": "Snare", "midi_output": {"midi_channel": 1, "note": 40}},
+                    {"divisors": [12], "sample": "HihatClosed", "velocity": 80, "muted": true},
+                    {"sample": "FmTom", "voice_params": {"tune": 90.0, "decay": 0.3, "tone": 4.0}}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let config = Config::new();
+        config.load_project(&project).unwrap();
+        assert_eq!(96, config.params.bpm.load());
+        assert_eq!(0b101, config.params.locked[0].load());
+        assert_eq!(Sound::Kit(4), config.params.channel_samples[0].load());
+        assert_eq!(NoteMode::Latch, config.params.channel_note_modes[0].load());
+        assert_eq!(Sound::Kit(2), config.params.channel_samples[1].load());
+        // missing channels get the kit's default
+        assert_eq!(Sound::Kit(3), config.params.channel_samples[4].load());
+        assert_eq!(
+            Sound::Voice(Voice::FmTom),
+            config.params.channel_samples[3].load()
+        );
+        assert_eq!(90f32, config.params.channel_voice_params[3].load().tune);
+        assert_eq!(1 << 11, config.params.locked[2].load());
+        assert_eq!(80, config.params.channel_velocities[2].load());
+        assert_eq!(0b100, config.params.muted.load());
+        assert_eq!(40, config.output_note(1).note);
+        assert_eq!(None, config.params.channel_output_notes[2].load());
+        assert_eq!(
+            OutputNote {
+                midi_channel: 10,
+                note: 42
+            },
+            config.output_note(2)
+        );
+        // the tom voice has no note in the kit, but is a general midi low tom
+        assert_eq!(45, config.output_note(3).note);
+
+        let saved = config.to_project();
+        let loaded = Project::from_json(&saved.to_json().unwrap()).unwrap();
+        assert_eq!(saved, loaded);
+        assert_eq!(&project.channels[..], &loaded.channels[..4]);
+    }
+
+    #[test]
+    fn invalid() {
+        let config = Config::new();
+        let project = Project::from_json(r#"{"channels": [{"divisors": [13]}]}"#).unwrap();
+        assert!(config.load_project(&project).is_err());
+        let project = Project::from_json(r#"{"channels": [{"sample": "Tuba"}]}"#).unwrap();
+        assert!(config.load_project(&project).is_err());
+        let project = Project::from_json(
+            r#"{"channels": [{"midi_output": {"midi_channel": 17, "note": 36}}]}"#,
+        )
+        .unwrap();
+        assert!(config.load_project(&project).is_err());
+        // voice params out of range would make the voices produce nan
+        let project = Project::from_json(
+            r#"{"channels": [{"sample": "AnalogKick", "voice_params": {"tune": 50.0, "decay": 0.0, "tone": 2.5}}]}"#,
+        )
+        .unwrap();
+        assert!(config.load_project(&project).is_err());
+        let project = Project::from_json(
+            r#"{"channels": [{"sample": "Clap", "voice_params": {"tune": 1200.0, "decay": 0.25, "tone": 0.0}}]}"#,
+        )
+        .unwrap();
+        assert!(config.load_project(&project).is_err());
+        assert_eq!(Sound::Kit(4), config.params.channel_samples[0].load());
+    }
+}
@@ -1,33 +1,88 @@
-use enum_map::{enum_map, EnumMap};
-use hound::WavReader;
+use super::{kit::Kit, voice::Voice, NUM_CHANNELS};
+use anyhow::{bail, Result};
+use hound::{SampleFormat, WavReader};
 use rubato::Resampler;
-use strum_macros::Display;
-
-#[derive(Copy, Clone, enum_map::Enum, Display, enum_iterator::Sequence, PartialEq)]
-#[repr(u8)]
-pub enum Sample {
-    HihatClosed,
-    HihatOpen,
-    Snare,
-    Cowbell,
-    Kick,
+use std::sync::Arc;
+
+/// What a channel plays, either a sample of the current kit, a synthesized voice or a sample loaded by the user.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Sound {
+    /// index into the samples of the current kit
+    Kit(u8),
+    Voice(Voice),
+    /// the sample loaded into this channel
+    User,
+}
+
+/// A wav file loaded by the user, mixed down to mono at its original sample rate.
+pub struct UserSample {
+    /// shown in the ui
+    pub name: String,
+    /// where it was loaded from, if known
+    pub path: Option<String>,
+    pub(super) data: Vec<f32>,
+    pub(super) sample_rate: u32,
 }
 
+impl UserSample {
+    pub fn from_wav(name: String, path: Option<String>, data: &[u8]) -> Result<Self> {
+        let (data, sample_rate) = decode_wav(data)?;
+        Ok(Self {
+            name,
+            path,
+            data,
+            sample_rate,
+        })
+    }
+}
+
+/// A user sample along with its resampled data.
+type ResampledUserSample = (Arc<UserSample>, Arc<[f32]>);
+
+/// The sounds resampled for playback at a given rate.
+/// Built by `Config` off the audio thread, cloning it only copies pointers.
 #[derive(Clone)]
 pub struct Bank {
-    samples: EnumMap<Sample, Vec<f32>>,
+    kit: Arc<Kit>,
+    /// resampled data of each layer of each sample in the kit
+    kit_samples: Arc<Vec<Vec<Vec<f32>>>>,
+    /// user samples along with their resampled data
+    user_samples: [Option<ResampledUserSample>; NUM_CHANNELS],
     sample_rate: u32,
 }
 
-fn sample_to_vec(data: &[u8], sample_rate: u32) -> Vec<f32> {
-    let wav = WavReader::new(data).unwrap();
-    let in_sample_rate = wav.spec().sample_rate as f64;
-    let num_samples = wav.len() as usize;
-    assert!(wav.spec().channels == 1);
-    let buf: Vec<f32> = wav
-        .into_samples::<i16>()
-        .map(|s| s.unwrap() as f32 / i16::MAX as f32)
+/// Decode a wav file of any bit depth and channel count to mono samples.
+/// Returns the samples and the sample rate of the file.
+pub fn decode_wav(data: &[u8]) -> Result<(Vec<f32>, u32)> {
+    let wav = WavReader::new(data)?;
+    let spec = wav.spec();
+    if spec.channels == 0 {
+        bail!("wav file has no channels");
+    }
+    let interleaved: Vec<f32> = match (spec.sample_format, spec.bits_per_sample) {
+        (SampleFormat::Float, 32) => wav.into_samples::<f32>().collect::<Result<_, _>>()?,
+        (SampleFormat::Int, bits @ 1..=32) => {
+            let scale = (1u64 << (bits - 1)) as f32;
+            wav.into_samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+        (format, bits) => bail!("unsupported wav format: {} bit {:?}", bits, format),
+    };
+    let channels = usize::from(spec.channels);
+    let mono: Vec<f32> = interleaved
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
         .collect();
+    if mono.is_empty() {
+        bail!("wav file is empty");
+    }
+    Ok((mono, spec.sample_rate))
+}
+
+/// Resample `buf` as if it was recorded at `in_sample_rate`, so a higher rate pitches it down.
+pub(super) fn resample(buf: Vec<f32>, in_sample_rate: f64, sample_rate: u32) -> Result<Vec<f32>> {
+    let num_samples = buf.len();
     // TODO use fft resampler instead? how to avoid it changing the timing?
     let mut resampler = rubato::SincFixedIn::new(
         sample_rate as f64 / in_sample_rate,
@@ -41,42 +96,133 @@ fn sample_to_vec(data: &[u8], sample_rate: u32) -> Vec<f32> {
         },
         num_samples,
         1, //< channels
-    )
-    .unwrap();
-    let out = resampler.process(&[buf], None).unwrap();
+    )?;
+    let out = resampler.process(&[buf], None)?;
     debug_assert!(out.len() == 1);
-    out.into_iter().next().unwrap()
+    Ok(out.into_iter().next().unwrap())
 }
 
 impl Bank {
-    pub fn new(sample_rate: u32) -> Self {
+    pub fn new(kit: Arc<Kit>, sample_rate: u32) -> Self {
         Bank {
-            samples: enum_map! {
-                Sample::HihatClosed => {
-                    sample_to_vec(include_bytes!("../../samples/hihat_closed.wav"), sample_rate)
-                },
-                Sample::HihatOpen => {
-                    sample_to_vec(include_bytes!("../../samples/hihat_open.wav"), sample_rate)
-                },
-                Sample::Snare => {
-                    sample_to_vec(include_bytes!("../../samples/snare.wav"), sample_rate)
-                },
-                Sample::Cowbell => {
-                    sample_to_vec(include_bytes!("../../samples/cowbell.wav"), sample_rate)
-                },
-                Sample::Kick => {
-                    sample_to_vec(include_bytes!("../../samples/kick.wav"), sample_rate)
-                },
-            },
+            kit_samples: Arc::new(kit.resample_all(sample_rate)),
+            kit,
+            user_samples: Default::default(),
             sample_rate,
         }
     }
 
-    pub fn get_sound(&self, idx: Sample) -> &[f32] {
-        &self.samples[idx]
+    /// Empty if the current kit doesn't have a sample at `slot`.
+    pub fn get_sound(&self, slot: u8, layer: usize) -> &[f32] {
+        self.kit_samples
+            .get(usize::from(slot))
+            .and_then(|layers| layers.get(layer))
+            .map(|data| data.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// See `KitSample::choose_layer`.
+    pub fn choose_layer(&self, slot: u8, velocity: u8, hits: u64, random: u32) -> usize {
+        self.kit
+            .samples
+            .get(usize::from(slot))
+            .map(|sample| sample.choose_layer(velocity, hits, random))
+            .unwrap_or(0)
+    }
+
+    /// Resample the kit if it has changed.
+    pub fn update_kit(&mut self, kit: Arc<Kit>) {
+        if !Arc::ptr_eq(&self.kit, &kit) {
+            self.kit_samples = Arc::new(kit.resample_all(self.sample_rate));
+            self.kit = kit;
+        }
+    }
+
+    /// Empty if no sample is loaded into the channel.
+    pub fn get_user_sound(&self, channel: usize) -> &[f32] {
+        self.user_samples[channel]
+            .as_ref()
+            .map(|(_, data)| &data[..])
+            .unwrap_or(&[])
+    }
+
+    /// Resample the user sample of a channel if it has changed.
+    pub fn update_user_sample(&mut self, channel: usize, sample: Option<Arc<UserSample>>) {
+        let current = self.user_samples[channel].as_ref().map(|(s, _)| s);
+        if current.map(Arc::as_ptr) == sample.as_ref().map(Arc::as_ptr) {
+            return;
+        }
+        self.user_samples[channel] = sample.and_then(|sample| {
+            match resample(
+                sample.data.clone(),
+                sample.sample_rate.into(),
+                self.sample_rate,
+            ) {
+                Ok(data) => Some((sample, data.into())),
+                Err(e) => {
+                    log::error!("unable to resample {}: {}", sample.name, e);
+                    None
+                }
+            }
+        });
     }
 
     pub fn get_sample_rate(&self) -> u32 {
         self.sample_rate
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::decode_wav;
+    use hound::{SampleFormat, WavSpec, WavWriter};
+    use std::io::Cursor;
+
+    fn write_wav(channels: u16, bits_per_sample: u16, sample_format: SampleFormat) -> Vec<u8> {
+        let mut buf = Cursor::new(Vec::new());
+        let mut writer = WavWriter::new(
+            &mut buf,
+            WavSpec {
+                channels,
+                sample_rate: 22050,
+                bits_per_sample,
+                sample_format,
+            },
+        )
+        .unwrap();
+        // left half scale, right silent
+        for _ in 0..10 {
+            for c in 0..channels {
+                let v = if c == 0 { 0.5 } else { 0.0 };
+                match (sample_format, bits_per_sample) {
+                    (SampleFormat::Float, _) => writer.write_sample(v as f32).unwrap(),
+                    (_, 8) => writer.write_sample((v * 128.0) as i8).unwrap(),
+                    (_, 16) => writer.write_sample((v * 32768.0) as i16).unwrap(),
+                    (_, bits) => writer
+                        .write_sample((v * (1i64 << (bits - 1)) as f64) as i32)
+                        .unwrap(),
+                }
+            }
+        }
+        writer.finalize().unwrap();
+        buf.into_inner()
+    }
+
+    #[test]
+    fn decode_formats() {
+        for (bits, format) in [
+            (8, SampleFormat::Int),
+            (16, SampleFormat::Int),
+            (24, SampleFormat::Int),
+            (32, SampleFormat::Int),
+            (32, SampleFormat::Float),
+        ] {
+            let (mono, rate) = decode_wav(&write_wav(1, bits, format)).unwrap();
+            assert_eq!(22050, rate);
+            assert_eq!(vec![0.5; 10], mono);
+            let (stereo, _) = decode_wav(&write_wav(2, bits, format)).unwrap();
+            assert_eq!(vec![0.25; 10], stereo);
+        }
+        assert!(decode_wav(b"not a wav file").is_err());
+    }
+}
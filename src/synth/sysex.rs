@@ -0,0 +1,306 @@
+//! Dump and restore of the pattern state using midi system exclusive messages.
+//!
+//! A message is `F0 7D 44 43 <version> <command> <data...> <checksum> F7`.
+//! 7D is the manufacturer id for non-commercial use, 44 43 is "DC" and the version is 1.
+//! The checksum makes the sum of the command, data and checksum bytes a multiple of 128.
+//!
+//! Commands:
+//! - 01, dump: the data is the state, see below.
+//! - 02, request: no data, answered with a dump to the midi output.
+//!
+//! The state is packed into 7 bit bytes by splitting it into groups of 7 bytes,
+//! each preceded by a byte holding their top bits, the first byte's in bit 0.
+//! Unpacked it is, with multi-byte numbers big endian:
+//! - bpm, u16
+//! - gain in hundredths of a dB, i16
+//! - number of channels, u8, followed by that many channels of
+//!   - locked notes, u16 with bit 0 for divisor 1
+//!   - volume in hundredths of a dB, i16
+//!   - velocity, u8
+//!   - muted, u8
+//!   - name of the sound, u8 length followed by utf-8
+//!
+//! A dump only replaces those parts of the state, everything else is kept.
+
+use super::{
+    project::{ChannelProject, Project},
+    Config, NUM_CHANNELS,
+};
+use anyhow::{anyhow, bail, Result};
+use log::warn;
+use wmidi::{MidiMessage, U7};
+
+const HEADER: [u8; 4] = [0x7d, 0x44, 0x43, 1];
+const DUMP: u8 = 1;
+const REQUEST: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// the parts of the state that are included in a dump
+    Dump(Project),
+    Request,
+}
+
+impl Message {
+    pub fn to_midi(&self) -> MidiMessage<'static> {
+        let mut data = HEADER.to_vec();
+        match self {
+            Message::Dump(state) => {
+                data.push(DUMP);
+                data.extend(pack(&encode_state(state)));
+            }
+            Message::Request => data.push(REQUEST),
+        }
+        let sum = data[HEADER.len()..]
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_add(b));
+        data.push(sum.wrapping_neg() & 0x7f);
+        MidiMessage::OwnedSysEx(data.into_iter().map(U7::from_u8_lossy).collect())
+    }
+
+    /// Decode the data of a sysex message, without F0 and F7.
+    /// None if the message is meant for something else.
+    pub fn from_sysex(data: &[U7]) -> Result<Option<Self>> {
+        let data = U7::data_to_bytes(data);
+        let Some(body) = data.strip_prefix(&HEADER[..3]) else {
+            return Ok(None);
+        };
+        let Some((&version, body)) = body.split_first() else {
+            bail!("sysex message too short");
+        };
+        if version != HEADER[3] {
+            bail!("unsupported sysex version {}", version);
+        }
+        if body.len() < 2 {
+            bail!("sysex message too short");
+        }
+        if body.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) & 0x7f != 0 {
+            bail!("sysex checksum mismatch");
+        }
+        let (&command, payload) = body[..body.len() - 1].split_first().unwrap();
+        match command {
+            DUMP => Ok(Some(Message::Dump(decode_state(&unpack(payload))?))),
+            REQUEST => Ok(Some(Message::Request)),
+            _ => bail!("unknown sysex command {}", command),
+        }
+    }
+}
+
+fn hundredths(value: f32) -> [u8; 2] {
+    ((value * 100f32).round() as i16).to_be_bytes()
+}
+
+fn encode_state(state: &Project) -> Vec<u8> {
+    let mut data = vec![];
+    data.extend((state.bpm.min(u16::MAX.into()) as u16).to_be_bytes());
+    data.extend(hundredths(state.gain_db));
+    data.push(state.channels.len() as u8);
+    for channel in &state.channels {
+        let locked = channel
+            .divisors
+            .iter()
+            .fold(0u16, |locked, divisor| locked | 1 << (divisor - 1));
+        data.extend(locked.to_be_bytes());
+        data.extend(hundredths(channel.volume_db));
+        data.push(channel.velocity);
+        data.push(channel.muted.into());
+        let name = channel.sample.as_deref().unwrap_or_default().as_bytes();
+        let name = &name[..name.len().min(u8::MAX.into())];
+        data.push(name.len() as u8);
+        data.extend(name);
+    }
+    data
+}
+
+fn decode_state(data: &[u8]) -> Result<Project> {
+    let mut data = data.iter().copied();
+    let mut next = || data.next().ok_or_else(|| anyhow!("sysex dump too short"));
+    let bpm = u16::from_be_bytes([next()?, next()?]);
+    let gain = i16::from_be_bytes([next()?, next()?]);
+    let num_channels = next()?;
+    let mut channels = vec![];
+    for _ in 0..num_channels {
+        let locked = u16::from_be_bytes([next()?, next()?]);
+        let volume = i16::from_be_bytes([next()?, next()?]);
+        let velocity = next()?;
+        let muted = next()? != 0;
+        let name = (0..next()?).map(|_| next()).collect::<Result<Vec<_>>>()?;
+        channels.push(ChannelProject {
+            divisors: (0..16)
+                .filter(|n| locked & (1 << n) != 0)
+                .map(|n| n + 1)
+                .collect(),
+            sample: Some(String::from_utf8(name)?),
+            volume_db: f32::from(volume) / 100f32,
+            velocity,
+            muted,
+            ..Default::default()
+        });
+    }
+    Ok(Project {
+        bpm: bpm.into(),
+        gain_db: f32::from(gain) / 100f32,
+        channels,
+        ..Default::default()
+    })
+}
+
+fn pack(data: &[u8]) -> Vec<u8> {
+    let mut packed = vec![];
+    for group in data.chunks(7) {
+        packed.push(
+            group
+                .iter()
+                .enumerate()
+                .fold(0, |top, (i, b)| top | (b >> 7) << i),
+        );
+        packed.extend(group.iter().map(|b| b & 0x7f));
+    }
+    packed
+}
+
+fn unpack(data: &[u8]) -> Vec<u8> {
+    data.chunks(8)
+        .flat_map(|group| {
+            let top = group[0];
+            group[1..]
+                .iter()
+                .enumerate()
+                .map(move |(i, b)| b | ((top >> i) & 1) << 7)
+        })
+        .collect()
+}
+
+impl Config {
+    pub fn state_dump(&self) -> Message {
+        let project = self.to_project();
+        Message::Dump(Project {
+            kit: None,
+            channels: project
+                .channels
+                .into_iter()
+                .map(|channel| ChannelProject {
+                    divisors: channel.divisors,
+                    sample: channel.sample,
+                    volume_db: channel.volume_db,
+                    velocity: channel.velocity,
+                    muted: channel.muted,
+                    ..Default::default()
+                })
+                .collect(),
+            ..project
+        })
+    }
+
+    /// Load a received dump, or answer a request with the dump to send back.
+    pub(super) fn handle_sysex(&self, data: &[U7]) -> Option<MidiMessage<'static>> {
+        match Message::from_sysex(data) {
+            Ok(Some(Message::Dump(state))) => {
+                if let Err(e) = self.load_state_dump(&state) {
+                    warn!("unable to load state dump: {}", e);
+                }
+                None
+            }
+            Ok(Some(Message::Request)) => Some(self.state_dump().to_midi()),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("invalid sysex message: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Apply a dump on top of the current state. Nothing is changed if it is invalid.
+    pub fn load_state_dump(&self, state: &Project) -> Result<()> {
+        if state.channels.len() > NUM_CHANNELS {
+            bail!(
+                "too many channels: {}, max is {}",
+                state.channels.len(),
+                NUM_CHANNELS
+            );
+        }
+        let mut project = self.to_project();
+        project.bpm = state.bpm;
+        project.gain_db = state.gain_db;
+        for (channel, dumped) in project.channels.iter_mut().zip(&state.channels) {
+            if channel.sample != dumped.sample {
+                // the voice params belonged to the previous sound
+                channel.voice_params = None;
+                channel.sample = dumped.sample.clone();
+            }
+            channel.divisors = dumped.divisors.clone();
+            channel.volume_db = dumped.volume_db;
+            channel.velocity = dumped.velocity;
+            channel.muted = dumped.muted;
+        }
+        self.load_project(&project)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pack, unpack, Message};
+    use crate::synth::{sound_bank::Sound, voice::Voice, Config};
+    use wmidi::{MidiMessage, U7};
+
+    fn data(message: MidiMessage<'_>) -> Vec<U7> {
+        match message {
+            MidiMessage::OwnedSysEx(data) => data,
+            _ => panic!("not a sysex message"),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let bytes = (0..=255).collect::<Vec<u8>>();
+        assert_eq!(bytes, unpack(&pack(&bytes)));
+
+        let config = Config::new();
+        config.params.bpm.store(97);
+        config.params.gain_db.store(-3.5);
+        config.params.locked[2].store(0b1000_0000_0101);
+        config.params.channel_volumes_db[1].store(-12.25);
+        config.params.channel_samples[3].store(Sound::Voice(Voice::FmTom));
+        config.params.muted.store(0b1001);
+        let dump = config.state_dump();
+        let message = dump.to_midi();
+        let bytes = message.to_vec();
+        assert_eq!([0xf0, 0x7d, 0x44, 0x43, 1, 1], bytes[..6]);
+        assert_eq!(Some(&0xf7), bytes.last());
+        assert!(bytes[1..bytes.len() - 1].iter().all(|&b| b < 0x80));
+
+        let loaded = Message::from_sysex(&data(message)).unwrap().unwrap();
+        assert_eq!(dump, loaded);
+        let other = Config::new();
+        other.params.channel_velocities[0].store(90);
+        let Message::Dump(state) = loaded else {
+            panic!("not a dump");
+        };
+        other.load_state_dump(&state).unwrap();
+        assert_eq!(97, other.params.bpm.load());
+        assert_eq!(-3.5, other.params.gain_db.load());
+        assert_eq!(0b1000_0000_0101, other.params.locked[2].load());
+        assert_eq!(-12.25, other.params.channel_volumes_db[1].load());
+        assert_eq!(
+            Sound::Voice(Voice::FmTom),
+            other.params.channel_samples[3].load()
+        );
+        assert_eq!(0b1001, other.params.muted.load());
+        assert_eq!(127, other.params.channel_velocities[0].load());
+
+        assert_eq!(
+            Some(Message::Request),
+            Message::from_sysex(&data(Message::Request.to_midi())).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let mut bytes = data(Config::new().state_dump().to_midi());
+        bytes[10] = U7::from_u8_lossy(u8::from(bytes[10]) ^ 1);
+        assert!(Message::from_sysex(&bytes).is_err());
+        // other manufacturers are ignored
+        let other = U7::try_from_bytes(&[0x41, 0x10, 0x42, 0x12]).unwrap();
+        assert_eq!(None, Message::from_sysex(other).unwrap());
+    }
+}
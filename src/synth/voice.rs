@@ -0,0 +1,240 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{f32::consts::TAU, ops::RangeInclusive};
+use strum_macros::Display;
+
+/// Drum sounds that are synthesized while playing instead of read from a sample.
+#[derive(
+    Copy, Clone, Debug, Display, enum_iterator::Sequence, PartialEq, Serialize, Deserialize,
+)]
+pub enum Voice {
+    AnalogKick,
+    NoiseSnare,
+    MetalHat,
+    FmTom,
+    Clap,
+}
+
+/// The meaning of each parameter depends on the voice, see `Voice::param_info`.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VoiceParams {
+    pub tune: f32,
+    pub decay: f32,
+    pub tone: f32,
+}
+
+pub struct ParamInfo {
+    pub name: &'static str,
+    pub range: RangeInclusive<f32>,
+    pub default: f32,
+}
+
+const fn param(name: &'static str, min: f32, max: f32, default: f32) -> ParamInfo {
+    ParamInfo {
+        name,
+        range: min..=max,
+        default,
+    }
+}
+
+// ratios of the square waves of the 808 hihat
+const HAT_RATIOS: [f32; 6] = [1.0, 1.5, 2.08, 2.715, 3.395, 4.105];
+const CLAP_BURSTS: f32 = 3f32;
+
+impl Voice {
+    /// Name, range and default value of tune, decay and tone.
+    pub fn param_info(self) -> [ParamInfo; 3] {
+        match self {
+            Voice::AnalogKick => [
+                param("tune", 30.0, 120.0, 50.0),
+                param("decay", 0.05, 2.0, 0.4),
+                param("punch", 0.0, 5.0, 2.5),
+            ],
+            Voice::NoiseSnare => [
+                param("tune", 100.0, 400.0, 180.0),
+                param("decay", 0.05, 1.0, 0.2),
+                param("noise", 0.0, 1.0, 0.7),
+            ],
+            Voice::MetalHat => [
+                param("tune", 200.0, 800.0, 400.0),
+                param("decay", 0.01, 1.0, 0.08),
+                param("bright", 0.0, 1.0, 0.8),
+            ],
+            Voice::FmTom => [
+                param("tune", 60.0, 400.0, 120.0),
+                param("decay", 0.05, 2.0, 0.5),
+                param("fm", 0.0, 10.0, 2.0),
+            ],
+            Voice::Clap => [
+                param("tune", 500.0, 3000.0, 1200.0),
+                param("decay", 0.05, 1.0, 0.25),
+                param("spread", 0.005, 0.03, 0.01),
+            ],
+        }
+    }
+
+    pub fn default_params(self) -> VoiceParams {
+        let [tune, decay, tone] = self.param_info();
+        VoiceParams {
+            tune: tune.default,
+            decay: decay.default,
+            tone: tone.default,
+        }
+    }
+
+    /// Each parameter must be within the range of its `param_info`.
+    pub fn validate_params(self, params: &VoiceParams) -> Result<()> {
+        for (info, value) in self
+            .param_info()
+            .iter()
+            .zip([params.tune, params.decay, params.tone])
+        {
+            if !info.range.contains(&value) {
+                bail!(
+                    "invalid {} {} of {}, must be between {} and {}",
+                    info.name,
+                    value,
+                    self,
+                    info.range.start(),
+                    info.range.end()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Length in seconds after which the voice is considered silent.
+    fn length(self, params: &VoiceParams) -> f32 {
+        let tail = params.decay * 8f32;
+        match self {
+            Voice::Clap => params.tone * CLAP_BURSTS + tail,
+            _ => tail,
+        }
+    }
+}
+
+/// Oscillator and filter state of a single hit of a voice.
+#[derive(Clone)]
+pub struct VoiceState {
+    phases: [f32; HAT_RATIOS.len()],
+    noise: u32,
+    low: f32,
+    band: f32,
+}
+
+impl VoiceState {
+    /// `seed` makes the noise deterministic, which keeps bounces reproducible.
+    pub fn new(seed: u32) -> Self {
+        Self {
+            phases: [0f32; HAT_RATIOS.len()],
+            // xorshift doesn't like 0
+            noise: seed | 1,
+            low: 0f32,
+            band: 0f32,
+        }
+    }
+
+    fn noise(&mut self) -> f32 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        self.noise as f32 / u32::MAX as f32 * 2f32 - 1f32
+    }
+
+    fn advance(&mut self, osc: usize, freq: f32, sample_rate: f32) -> f32 {
+        let phase = self.phases[osc];
+        self.phases[osc] = (phase + freq / sample_rate).fract();
+        phase
+    }
+
+    /// State variable filter, returns (lowpass, bandpass, highpass).
+    fn filter(&mut self, input: f32, cutoff: f32, sample_rate: f32) -> (f32, f32, f32) {
+        let f = 2f32 * (std::f32::consts::PI * (cutoff / sample_rate).min(0.25)).sin();
+        const DAMPING: f32 = 0.5;
+        self.low += f * self.band;
+        let high = input - self.low - DAMPING * self.band;
+        self.band += f * high;
+        (self.low, self.band, high)
+    }
+
+    /// Produce the sample at `frame` frames since the hit, or None if the voice has finished.
+    pub fn next(
+        &mut self,
+        voice: Voice,
+        params: &VoiceParams,
+        sample_rate: u32,
+        frame: u64,
+    ) -> Option<f32> {
+        let sr = sample_rate as f32;
+        let t = frame as f32 / sr;
+        if t > voice.length(params) {
+            return None;
+        }
+        let env = (-t / params.decay).exp();
+        Some(match voice {
+            Voice::AnalogKick => {
+                let freq = params.tune * 2f32.powf(params.tone * (-t / 0.03).exp());
+                (self.advance(0, freq, sr) * TAU).sin() * env
+            }
+            Voice::NoiseSnare => {
+                let body = (self.advance(0, params.tune, sr) * TAU).sin()
+                    * (-t / (params.decay * 0.5)).exp();
+                let noise = self.noise();
+                let (_, _, high) = self.filter(noise, 1000f32, sr);
+                // the highpassed noise peaks well above 1
+                ((1f32 - params.tone) * body + params.tone * 0.5 * high) * env
+            }
+            Voice::MetalHat => {
+                let mut metal = 0f32;
+                for (osc, ratio) in HAT_RATIOS.iter().enumerate() {
+                    let square = if self.advance(osc, params.tune * ratio, sr) < 0.5 {
+                        1f32
+                    } else {
+                        -1f32
+                    };
+                    metal += square / HAT_RATIOS.len() as f32;
+                }
+                let (_, band, high) = self.filter(metal, 7000f32, sr);
+                (params.tone * high + (1f32 - params.tone) * band) * env
+            }
+            Voice::FmTom => {
+                let freq = params.tune * (1f32 + 0.5 * (-t / 0.05).exp());
+                let modulator = (self.advance(1, freq * 1.5, sr) * TAU).sin();
+                (self.advance(0, freq, sr) * TAU + params.tone * env * modulator).sin() * env
+            }
+            Voice::Clap => {
+                let noise = self.noise();
+                let (_, band, _) = self.filter(noise, params.tune, sr);
+                let bursts_end = params.tone * CLAP_BURSTS;
+                let clap_env = if t < bursts_end {
+                    (-(t % params.tone) / 0.008).exp()
+                } else {
+                    (-(t - bursts_end) / params.decay).exp()
+                };
+                band * clap_env
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Voice, VoiceState};
+
+    #[test]
+    fn voices_end() {
+        for voice in enum_iterator::all::<Voice>() {
+            let params = voice.default_params();
+            let mut state = VoiceState::new(1);
+            let mut frame = 0;
+            let mut peak = 0f32;
+            while let Some(v) = state.next(voice, &params, 48000, frame) {
+                assert!(v.is_finite());
+                peak = peak.max(v.abs());
+                frame += 1;
+            }
+            assert!(peak > 0.05 && peak < 2f32, "{} {}", voice, peak);
+            assert!(frame > 0 && frame < 48000 * 20);
+        }
+    }
+}
@@ -24,6 +24,12 @@ cfg_if::cfg_if! {
                 win.set_timeout_with_callback_and_timeout_and_arguments_0(rcf.borrow().as_ref().unwrap().as_ref().unchecked_ref(), delay.num_milliseconds() as i32).unwrap();
             }
         }
+
+        /// Microseconds on a monotonic clock shared by the midi inputs and the synth.
+        pub fn now_us() -> u64 {
+            let performance = web_sys::window().and_then(|w| w.performance());
+            performance.map(|p| (p.now() * 1000.) as u64).unwrap_or(0)
+        }
     } else {
         use std::thread;
         // TODO use a proper timer implementation instead. use the Timer crate?
@@ -41,5 +47,11 @@ cfg_if::cfg_if! {
                 });
             }
         }
+
+        /// Microseconds on a monotonic clock shared by the midi inputs and the synth.
+        pub fn now_us() -> u64 {
+            static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+            START.get_or_init(std::time::Instant::now).elapsed().as_micros() as u64
+        }
     }
 }